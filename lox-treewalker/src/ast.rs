@@ -1,4 +1,6 @@
-use crate::token::Token;
+use std::rc::Rc;
+
+use crate::token::{Token, TokenType};
 
 #[derive(Clone, PartialEq)]
 pub enum Expr {
@@ -16,13 +18,43 @@ pub enum Expr {
         callee: Box<Expr>,
         paren: Token,
         arguments: Vec<Expr>,
+        // `name: value` arguments, which come after the positional ones
+        named: Vec<(Token, Expr)>,
     },
     Get {
         object: Box<Expr>,
         name: Token,
     },
-    Grouping(Box<Expr>),
-    Literal(Literal),
+    Grouping {
+        expression: Box<Expr>,
+        line: u32,
+    },
+    Index {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+    },
+    IndexSet {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    List {
+        bracket: Token,
+        elements: Vec<Expr>,
+    },
+    Literal {
+        value: Literal,
+        line: u32,
+    },
+    // `list[start:end]`, either bound can be left out
+    Slice {
+        object: Box<Expr>,
+        bracket: Token,
+        start: Option<Box<Expr>>,
+        end: Option<Box<Expr>>,
+    },
     Logical {
         left: Box<Expr>,
         operator: Token,
@@ -47,6 +79,34 @@ pub enum Expr {
         right: Box<Expr>,
     },
     Variable(VariableExpr),
+    // Turns the value into a string, for the expressions in an interpolated string
+    Stringify(Box<Expr>),
+}
+
+impl Expr {
+    // The line the expression starts on. Every variant holds a token or a line to get it from.
+    // There is no column, since tokens don't have one and errors are only reported by line
+    pub fn line(&self) -> u32 {
+        match self {
+            Expr::Assign { name, .. } => name.line,
+            Expr::Binary { left, .. } => left.line(),
+            Expr::Call { callee, .. } => callee.line(),
+            Expr::Get { object, .. } => object.line(),
+            Expr::Grouping { line, .. } => *line,
+            Expr::Index { object, .. } => object.line(),
+            Expr::IndexSet { object, .. } => object.line(),
+            Expr::List { bracket, .. } => bracket.line,
+            Expr::Literal { line, .. } => *line,
+            Expr::Slice { object, .. } => object.line(),
+            Expr::Logical { left, .. } => left.line(),
+            Expr::Set { object, .. } => object.line(),
+            Expr::Super { keyword, .. } => keyword.line,
+            Expr::This { keyword, .. } => keyword.line,
+            Expr::Unary { operator, .. } => operator.line,
+            Expr::Variable(variable) => variable.name.line,
+            Expr::Stringify(expression) => expression.line(),
+        }
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -58,8 +118,25 @@ pub struct VariableExpr {
 #[derive(Clone)]
 pub struct StmtFunction {
     pub name: Token,
-    pub params: Vec<Token>,
-    pub body: Vec<Stmt>,
+    // Shared with every `Function` made from this declaration, so creating closures and binding
+    // methods doesn't copy them
+    pub params: Rc<[Param]>,
+    // Collects any arguments after `params` into a list
+    pub rest: Option<Token>,
+    pub body: Rc<[Stmt]>,
+}
+
+#[derive(Clone)]
+pub struct Param {
+    pub name: Token,
+    pub default: Option<Expr>,
+}
+
+// A `var name = initializer;` in a class body, set on every new instance before `init` runs
+#[derive(Clone)]
+pub struct Field {
+    pub name: Token,
+    pub initializer: Option<Expr>,
 }
 
 #[derive(Clone)]
@@ -68,10 +145,30 @@ pub enum Stmt {
     Class {
         name: Token,
         methods: Vec<StmtFunction>,
+        fields: Vec<Field>,
         superclass: Option<VariableExpr>,
     },
     Expression(Expr),
+    // `for (initializer; condition; increment) body`. The initializer is a `var` or an expression
+    // statement, and no condition means loop forever
+    For {
+        keyword: Token,
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    ForEach {
+        name: Token,
+        iterable: Expr,
+        body: Box<Stmt>,
+    },
     Function(StmtFunction),
+    // Runs the top-level code of another file in the global environment
+    Import {
+        keyword: Token,
+        path: String,
+    },
     If {
         condition: Expr,
         then_branch: Box<Stmt>,
@@ -79,12 +176,32 @@ pub enum Stmt {
     },
     Return {
         keyword: Token,
-        value: Expr,
+        // `None` for a bare `return;`
+        value: Option<Expr>,
     },
     Print(Expr),
+    // Only the first matching case runs, there is no fallthrough
+    Switch {
+        subject: Expr,
+        cases: Vec<(Expr, Vec<Stmt>)>,
+        default: Option<Vec<Stmt>>,
+    },
+    // Raises the value as an error, for a `try` to catch
+    Throw {
+        keyword: Token,
+        value: Expr,
+    },
+    // When a runtime error escapes `body`, `handler` runs with the error bound to `name`
+    Try {
+        body: Vec<Stmt>,
+        name: Token,
+        handler: Vec<Stmt>,
+    },
+    // `const` declarations always have an initializer, and the resolver rejects assigning to them
     Var {
         name: Token,
         initializer: Option<Expr>,
+        constant: bool,
     },
     While {
         condition: Expr,
@@ -95,7 +212,7 @@ pub enum Stmt {
 #[derive(Clone, PartialEq)]
 pub enum Literal {
     Number(f64),
-    String(String),
+    String(Rc<str>),
     Bool(bool),
     Nil,
 }
@@ -104,38 +221,162 @@ impl std::fmt::Display for Literal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Literal::Number(number) => write!(f, "{}", number),
-            Literal::String(string) => write!(f, "\"{}\"", string),
+            Literal::String(string) => write!(f, "\"{}\"", escape_string(string)),
             Literal::Bool(bool) => write!(f, "{}", bool),
             Literal::Nil => write!(f, "nil"),
         }
     }
 }
 
-// impl std::fmt::Display for Expr {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         match self {
-//             Expr::Binary {
-//                 left,
-//                 operator,
-//                 right,
-//             } => write_parenthesize(f, &operator.lexeme, &[left, right]),
-//             Expr::Grouping(expression) => write_parenthesize(f, "group", &[expression]),
-//             Expr::Literal(literal) => write!(f, "{}", literal),
-//             Expr::Unary { operator, right } => write_parenthesize(f, &operator.lexeme, &[right]),
-//         }
-//     }
-// }
-
-// fn write_parenthesize(
-//     f: &mut std::fmt::Formatter<'_>,
-//     name: &str,
-//     exprs: &[&Expr],
-// ) -> std::fmt::Result {
-//     write!(f, "({}", name)?;
-//     for expr in exprs {
-//         write!(f, " {}", expr)?;
-//     }
-//     write!(f, ")")?;
-
-//     Ok(())
-// }
+// Renders the expression as Lox source that parses back to the same expression
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Assign { name, value, .. } => write!(f, "{} = {}", name.lexeme, value),
+            Expr::Binary {
+                operator:
+                    Token {
+                        typ: TokenType::Plus,
+                        ..
+                    },
+                ..
+            } => write_sum(f, self),
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => write!(f, "{} {} {}", left, operator.lexeme, right),
+            Expr::Call {
+                callee,
+                arguments,
+                named,
+                ..
+            } => {
+                write!(f, "{}(", callee)?;
+                write_list(f, arguments)?;
+                for (i, (name, value)) in named.iter().enumerate() {
+                    if i > 0 || !arguments.is_empty() {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", name.lexeme, value)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Get { object, name } => write!(f, "{}.{}", object, name.lexeme),
+            Expr::Grouping { expression, .. } => write!(f, "({})", expression),
+            Expr::Index { object, index, .. } => write!(f, "{}[{}]", object, index),
+            Expr::IndexSet {
+                object,
+                index,
+                value,
+                ..
+            } => write!(f, "{}[{}] = {}", object, index, value),
+            Expr::List { elements, .. } => {
+                write!(f, "[")?;
+                write_list(f, elements)?;
+                write!(f, "]")
+            }
+            Expr::Literal { value, .. } => write!(f, "{}", value),
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => write!(f, "{} {} {}", left, operator.lexeme, right),
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => write!(f, "{}.{} = {}", object, name.lexeme, value),
+            Expr::Slice {
+                object, start, end, ..
+            } => {
+                write!(f, "{}[", object)?;
+                if let Some(start) = start {
+                    write!(f, "{}", start)?;
+                }
+                write!(f, ":")?;
+                if let Some(end) = end {
+                    write!(f, "{}", end)?;
+                }
+                write!(f, "]")
+            }
+            Expr::Super { method, .. } => write!(f, "super.{}", method.lexeme),
+            Expr::This { .. } => write!(f, "this"),
+            Expr::Unary { operator, right } => write!(f, "{}{}", operator.lexeme, right),
+            Expr::Variable(variable) => write!(f, "{}", variable.name.lexeme),
+            // Only made as part of an interpolated string, see `write_sum`
+            Expr::Stringify(expression) => write!(f, "\"${{{}}}\"", expression),
+        }
+    }
+}
+
+fn write_list(f: &mut std::fmt::Formatter<'_>, exprs: &[Expr]) -> std::fmt::Result {
+    for (i, expr) in exprs.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", expr)?;
+    }
+    Ok(())
+}
+
+// `a + b + c`. An interpolated string is parsed as a sum starting with `"text" + ${expr}`, so
+// that part is written back as the string it came from
+fn write_sum(f: &mut std::fmt::Formatter<'_>, expr: &Expr) -> std::fmt::Result {
+    // `a + b + c` is `(a + b) + c`, so the terms are found down the left side
+    let mut terms = Vec::new();
+    let mut left = expr;
+    while let Expr::Binary {
+        left: inner,
+        operator: Token {
+            typ: TokenType::Plus,
+            ..
+        },
+        right,
+    } = left
+    {
+        terms.push(right.as_ref());
+        left = inner;
+    }
+    terms.push(left);
+    terms.reverse();
+
+    let mut rest = &terms[..];
+    if let [Expr::Literal {
+        value: Literal::String(first),
+        ..
+    }, Expr::Stringify(_), ..] = rest
+    {
+        write!(f, "\"{}", escape_string(first))?;
+        rest = &rest[1..];
+        while let [Expr::Stringify(expression), Expr::Literal {
+            value: Literal::String(text),
+            ..
+        }, ..] = rest
+        {
+            write!(f, "${{{}}}{}", expression, escape_string(text))?;
+            rest = &rest[2..];
+        }
+        write!(f, "\"")?;
+        for term in rest {
+            write!(f, " + {}", term)?;
+        }
+        return Ok(());
+    }
+
+    for (i, term) in terms.iter().enumerate() {
+        if i > 0 {
+            write!(f, " + ")?;
+        }
+        write!(f, "{}", term)?;
+    }
+    Ok(())
+}
+
+// Escapes what would end the string, start an escape or start an interpolation when parsed again
+pub fn escape_string(string: &str) -> String {
+    string
+        .replace('\\', "\\u{5C}")
+        .replace('"', "\\u{22}")
+        .replace("${", "\\${")
+}
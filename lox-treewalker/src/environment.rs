@@ -1,4 +1,8 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
 use crate::{
     runtime_error::{Result, RuntimeError},
@@ -9,6 +13,9 @@ use crate::{
 pub struct Environment {
     values: HashMap<String, Value>,
     enclosing: Option<Rc<RefCell<Environment>>>,
+    // The variables declared with `const`. The resolver rejects assigning to the ones it knows
+    // about, this catches the rest, like constants from an imported file
+    constants: HashSet<String>,
 }
 
 impl Environment {
@@ -16,6 +23,7 @@ impl Environment {
         Environment {
             values: HashMap::new(),
             enclosing: None,
+            constants: HashSet::new(),
         }
     }
 
@@ -23,16 +31,39 @@ impl Environment {
         Self {
             values: HashMap::new(),
             enclosing: Some(Rc::clone(enclosing)),
+            constants: HashSet::new(),
         }
     }
 
+    // Declaring the name again replaces the old variable, constant or not
     pub fn define(&mut self, name: &str, value: Value) {
+        self.constants.remove(name);
+        self.values.insert(name.to_owned(), value);
+    }
+
+    pub fn define_constant(&mut self, name: &str, value: Value) {
         self.values.insert(name.to_owned(), value);
+        self.constants.insert(name.to_owned());
+    }
+
+    // Only looks in this environment, not the enclosing ones
+    pub fn is_constant(&self, name: &str) -> bool {
+        self.constants.contains(name)
+    }
+
+    // Only looks in this environment, not the enclosing ones
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.values.contains_key(name)
     }
 
     pub fn assign(&mut self, name: &Token, value: Value) -> Result<()> {
-        if self.values.contains_key(&name.lexeme) {
-            self.values.insert(name.lexeme.clone(), value);
+        if self.constants.contains(&name.lexeme) {
+            Err(RuntimeError::new(
+                name.clone(),
+                format!("Cannot assign to constant '{}'", name.lexeme),
+            ))
+        } else if let Some(slot) = self.values.get_mut(&name.lexeme) {
+            *slot = value;
             Ok(())
         } else {
             if let Some(enclosing) = self.enclosing.as_ref() {
@@ -72,10 +103,13 @@ pub fn assign_at(
     name: &Token,
     value: Value,
 ) {
-    anchestor(environment, distance)
-        .borrow_mut()
+    let environment = anchestor(environment, distance);
+    let mut environment = environment.borrow_mut();
+    // The resolver found the variable here, so it is already defined
+    *environment
         .values
-        .insert(name.lexeme.clone(), value);
+        .get_mut(&name.lexeme)
+        .expect("Could not find variable at distance") = value;
 }
 
 fn anchestor(environment: Rc<RefCell<Environment>>, distance: usize) -> Rc<RefCell<Environment>> {
@@ -1,15 +1,103 @@
+/// An error or a warning found before the program runs, by the scanner, the parser or the
+/// resolver
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub line: u32,
+    // The token the error points at. None if there is no token, like for scanner errors
+    pub location: Option<Location>,
+    pub message: String,
+    pub severity: Severity,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    // Warnings don't stop the program from running
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    Token(String),
+    End,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Token(lexeme) => write!(f, " at '{}'", lexeme),
+            Location::End => write!(f, " at end"),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.severity == Severity::Warning {
+            return write!(f, "[line {}] Warning: {}", self.line, self.message);
+        }
+
+        write!(
+            f,
+            "{}",
+            format_err(
+                self.line,
+                &self
+                    .location
+                    .as_ref()
+                    .map(|location| location.to_string())
+                    .unwrap_or_default(),
+                &self.message
+            )
+        )
+    }
+}
+
+// Collects the errors instead of printing them, so the caller decides how to show them
 pub struct ErrorReporter {
-    pub had_error: bool,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl ErrorReporter {
+    pub fn new() -> ErrorReporter {
+        ErrorReporter {
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn had_error(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    // Splits what was found into the errors and the warnings
+    pub fn into_errors_and_warnings(self) -> (Vec<Diagnostic>, Vec<Diagnostic>) {
+        self.diagnostics
+            .into_iter()
+            .partition(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
     pub fn error(&mut self, line: u32, message: String) {
-        self.report(line, "", &message);
+        self.report(line, None, message);
+    }
+
+    pub fn warning(&mut self, line: u32, message: String) {
+        self.diagnostics.push(Diagnostic {
+            line,
+            location: None,
+            message,
+            severity: Severity::Warning,
+        });
     }
 
-    fn report(&mut self, line: u32, location: &str, message: &str) {
-        println!("{}", format_err(line, location, message));
-        self.had_error = true;
+    pub fn report(&mut self, line: u32, location: Option<Location>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            line,
+            location,
+            message,
+            severity: Severity::Error,
+        });
     }
 }
 
@@ -0,0 +1,256 @@
+use crate::ast::{escape_string, Field, Param, Stmt, StmtFunction, VariableExpr};
+
+const INDENT: &str = "  ";
+
+// Prints the statements back as consistently indented Lox source. Comments and blank lines are
+// lost in scanning, so a blank line is put around every function and class instead, and
+// `--fmt` warns about files with comments
+pub fn format_source(statements: &[Stmt]) -> String {
+    let mut formatter = Formatter {
+        lines: Vec::new(),
+        depth: 0,
+    };
+    formatter.statements(statements);
+
+    let mut source = formatter.lines.join("\n");
+    source.push('\n');
+    source
+}
+
+struct Formatter {
+    lines: Vec<String>,
+    depth: usize,
+}
+
+impl Formatter {
+    fn line(&mut self, text: impl AsRef<str>) {
+        self.lines
+            .push(format!("{}{}", INDENT.repeat(self.depth), text.as_ref()));
+    }
+
+    fn blank_line(&mut self) {
+        self.lines.push(String::new());
+    }
+
+    fn indented(&mut self, fun: impl FnOnce(&mut Self)) {
+        self.depth += 1;
+        fun(self);
+        self.depth -= 1;
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) {
+        for (i, statement) in statements.iter().enumerate() {
+            if i > 0 && (is_declaration(statement) || is_declaration(&statements[i - 1])) {
+                self.blank_line();
+            }
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Block(statements) => {
+                self.line("{");
+                self.indented(|f| f.statements(statements));
+                self.line("}");
+            }
+            Stmt::Class {
+                name,
+                methods,
+                fields,
+                superclass,
+            } => self.class(&name.lexeme, superclass.as_ref(), fields, methods),
+            Stmt::Expression(expr) => self.line(format!("{};", expr)),
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                let initializer = match initializer.as_deref() {
+                    Some(Stmt::Var {
+                        name,
+                        initializer: Some(value),
+                        ..
+                    }) => format!("var {} = {};", name.lexeme, value),
+                    Some(Stmt::Var { name, .. }) => format!("var {};", name.lexeme),
+                    Some(Stmt::Expression(expr)) => format!("{};", expr),
+                    _ => ";".to_owned(),
+                };
+                let condition = condition.as_ref().map(|c| format!(" {}", c));
+                let increment = increment.as_ref().map(|i| format!(" {}", i));
+                let head = format!(
+                    "for ({}{};{})",
+                    initializer,
+                    condition.unwrap_or_default(),
+                    increment.unwrap_or_default()
+                );
+                self.body(head, body)
+            }
+            Stmt::ForEach {
+                name,
+                iterable,
+                body,
+            } => self.body(format!("for ({} in {})", name.lexeme, iterable), body),
+            Stmt::Function(function) => self.function("fun ", function),
+            Stmt::Import { path, .. } => self.line(format!("import \"{}\";", escape_string(path))),
+            Stmt::If { .. } => self.if_statement("", statement),
+            Stmt::Return { value: None, .. } => self.line("return;"),
+            Stmt::Return {
+                value: Some(value), ..
+            } => self.line(format!("return {};", value)),
+            Stmt::Print(expr) => self.line(format!("print {};", expr)),
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                self.line(format!("switch ({}) {{", subject));
+                self.indented(|f| {
+                    for (value, statements) in cases {
+                        f.line(format!("case {}:", value));
+                        f.indented(|f| f.statements(statements));
+                    }
+                    if let Some(statements) = default {
+                        f.line("default:");
+                        f.indented(|f| f.statements(statements));
+                    }
+                });
+                self.line("}");
+            }
+            Stmt::Throw { value, .. } => self.line(format!("throw {};", value)),
+            Stmt::Try {
+                body,
+                name,
+                handler,
+            } => {
+                self.line("try {");
+                self.indented(|f| f.statements(body));
+                self.line(format!("}} catch ({}) {{", name.lexeme));
+                self.indented(|f| f.statements(handler));
+                self.line("}");
+            }
+            Stmt::Var {
+                name,
+                initializer: None,
+                ..
+            } => self.line(format!("var {};", name.lexeme)),
+            Stmt::Var {
+                name,
+                initializer: Some(initializer),
+                constant,
+            } => {
+                let keyword = if *constant { "const" } else { "var" };
+                self.line(format!("{} {} = {};", keyword, name.lexeme, initializer))
+            }
+            Stmt::While { condition, body } => self.body(format!("while ({})", condition), body),
+        }
+    }
+
+    // `if (a) {` or, continuing an else, `} else if (a) {`
+    fn if_statement(&mut self, prefix: &str, statement: &Stmt) {
+        let (condition, then_branch, else_branch) = match statement {
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => (condition, then_branch, else_branch),
+            _ => return self.statement(statement),
+        };
+
+        self.body(format!("{}if ({})", prefix, condition), then_branch);
+
+        let else_branch = match else_branch {
+            Some(else_branch) => else_branch,
+            None => return,
+        };
+        // The else goes on the line closing the then block, if there is one
+        let prefix = match **then_branch {
+            Stmt::Block(_) => {
+                self.lines.pop();
+                "} else "
+            }
+            _ => "else ",
+        };
+        match **else_branch {
+            Stmt::If { .. } => self.if_statement(prefix, else_branch),
+            _ => self.body(prefix.trim_end().to_owned(), else_branch),
+        }
+    }
+
+    // The body of an `if`, `while` or `for`. A block starts on the same line as the head, any
+    // other statement is indented on the next line
+    fn body(&mut self, head: String, body: &Stmt) {
+        match body {
+            Stmt::Block(statements) => {
+                self.line(format!("{} {{", head));
+                self.indented(|f| f.statements(statements));
+                self.line("}");
+            }
+            _ => {
+                self.line(head);
+                self.indented(|f| f.statement(body));
+            }
+        }
+    }
+
+    fn function(&mut self, keyword: &str, function: &StmtFunction) {
+        let mut params = function.params.iter().map(param).collect::<Vec<_>>();
+        if let Some(rest) = &function.rest {
+            params.push(format!("{}...", rest.lexeme));
+        }
+
+        self.line(format!(
+            "{}{}({}) {{",
+            keyword,
+            function.name.lexeme,
+            params.join(", ")
+        ));
+        self.indented(|f| f.statements(&function.body));
+        self.line("}");
+    }
+
+    fn class(
+        &mut self,
+        name: &str,
+        superclass: Option<&VariableExpr>,
+        fields: &[Field],
+        methods: &[StmtFunction],
+    ) {
+        match superclass {
+            Some(superclass) => {
+                self.line(format!("class {} < {} {{", name, superclass.name.lexeme))
+            }
+            None => self.line(format!("class {} {{", name)),
+        }
+        self.indented(|f| {
+            for field in fields {
+                match &field.initializer {
+                    Some(initializer) => {
+                        f.line(format!("var {} = {};", field.name.lexeme, initializer))
+                    }
+                    None => f.line(format!("var {};", field.name.lexeme)),
+                }
+            }
+            for (i, method) in methods.iter().enumerate() {
+                if i > 0 || !fields.is_empty() {
+                    f.blank_line();
+                }
+                f.function("", method);
+            }
+        });
+        self.line("}");
+    }
+}
+
+fn param(param: &Param) -> String {
+    match &param.default {
+        Some(default) => format!("{} = {}", param.name.lexeme, default),
+        None => param.name.lexeme.clone(),
+    }
+}
+
+fn is_declaration(statement: &Stmt) -> bool {
+    matches!(statement, Stmt::Function(_) | Stmt::Class { .. })
+}
@@ -1,8 +1,9 @@
 use std::{
     cell::RefCell,
     collections::HashMap,
+    path::{Path, PathBuf},
     rc::Rc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 // TODO: Change to having environment as a parameter to the function
@@ -10,6 +11,10 @@ use std::{
 use crate::{
     ast::{Expr, Literal, Stmt, VariableExpr},
     environment::{assign_at, get_at, Environment},
+    error_reporter::{Diagnostic, ErrorReporter},
+    parser::Parser,
+    pipeline::{parse, scan, RunError},
+    resolver::Resolver,
     runtime_error::RuntimeError,
     token::{Token, TokenType},
     value::{Class, Function, Value},
@@ -17,52 +22,385 @@ use crate::{
 
 type Result<T, E = RuntimeError> = std::result::Result<T, E>;
 
+// Puts the previous environment back when dropped, so leaving a block early with a return or an
+// error can't leave the interpreter in the block's environment
+struct EnvironmentGuard<'a> {
+    interpreter: &'a mut Interpreter,
+    previous: Option<Rc<RefCell<Environment>>>,
+}
+
+impl std::ops::Deref for EnvironmentGuard<'_> {
+    type Target = Interpreter;
+
+    fn deref(&self) -> &Interpreter {
+        self.interpreter
+    }
+}
+
+impl std::ops::DerefMut for EnvironmentGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Interpreter {
+        self.interpreter
+    }
+}
+
+impl Drop for EnvironmentGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            self.interpreter.environment = previous;
+        }
+    }
+}
+
+// Every Lox call recurses through `evaluate` and `execute`, so this has to be low enough that
+// the interpreter thread's stack (see `main`) doesn't run out first
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+// Repeating a string past this many bytes is an error, rather than the allocation failing and
+// taking down the whole interpreter
+const MAX_REPEATED_STRING_LENGTH: usize = 1 << 30;
+
+/// Limits for running scripts that can't be trusted to finish on their own. Each call to
+/// `Interpreter::interpret`, `eval_expression` or `eval` gets the full budget. `None` means no limit.
+#[derive(Clone, Default)]
+pub struct Limits {
+    pub max_statements: Option<u64>,
+    pub max_output_bytes: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
 pub struct Interpreter {
     environment: Rc<RefCell<Environment>>,
     globals: Rc<RefCell<Environment>>,
     locals: HashMap<usize, usize>,
+    // Only `Some` when running with `--time`
+    profile: Option<HashMap<String, CallProfile>>,
+    call_depth: usize,
+    max_call_depth: usize,
+    // Whether the resolver reports globals that are never declared
+    check_globals: bool,
+    // The files currently being run, innermost last. Imports are relative to the last one,
+    // and importing a file that is already here would never end
+    files: Vec<PathBuf>,
+    limits: Limits,
+    statements_executed: u64,
+    output_bytes: usize,
+    deadline: Option<Instant>,
+    // Found by the resolver and not yet taken with `take_warnings`
+    warnings: Vec<Diagnostic>,
+}
+
+#[derive(Default)]
+pub struct CallProfile {
+    calls: usize,
+    time: Duration,
 }
 
 impl Interpreter {
     pub fn new() -> Self {
         let mut globals = Environment::new();
 
+        // Seconds since the unix epoch, with a fractional part, like `clock` in the book
         globals.define(
             "clock",
             Value::BuiltinCallable {
-                arity: 0,
+                arity: 0..=0,
                 fun: |_, _| {
-                    Value::Number(
+                    Ok(Value::Number(
                         SystemTime::now()
                             .duration_since(UNIX_EPOCH)
                             .expect("time went backward!")
-                            .as_millis() as f64,
-                    )
+                            .as_secs_f64(),
+                    ))
                 },
             },
         );
 
+        globals.define(
+            "sleep",
+            Value::BuiltinCallable {
+                arity: 1..=1,
+                fun: sleep,
+            },
+        );
+
+        globals.define(
+            "write",
+            Value::BuiltinCallable {
+                arity: 1..=1,
+                fun: write,
+            },
+        );
+
+        globals.define(
+            "split",
+            Value::BuiltinCallable {
+                arity: 2..=2,
+                fun: split,
+            },
+        );
+
+        globals.define(
+            "join",
+            Value::BuiltinCallable {
+                arity: 2..=2,
+                fun: join,
+            },
+        );
+
+        globals.define(
+            "assert",
+            Value::BuiltinCallable {
+                arity: 1..=2,
+                fun: assert,
+            },
+        );
+
+        globals.define(
+            "approxEq",
+            Value::BuiltinCallable {
+                arity: 2..=3,
+                fun: approx_eq,
+            },
+        );
+
+        globals.define(
+            "isNaN",
+            Value::BuiltinCallable {
+                arity: 1..=1,
+                fun: is_nan,
+            },
+        );
+
+        globals.define(
+            "isFinite",
+            Value::BuiltinCallable {
+                arity: 1..=1,
+                fun: is_finite,
+            },
+        );
+
+        globals.define(
+            "clone",
+            Value::BuiltinCallable {
+                arity: 1..=2,
+                fun: clone,
+            },
+        );
+
+        globals.define(
+            "fields",
+            Value::BuiltinCallable {
+                arity: 1..=1,
+                fun: fields,
+            },
+        );
+
         let globals = Rc::new(RefCell::new(globals));
 
         Interpreter {
             environment: globals.clone(),
             globals,
             locals: HashMap::new(),
+            profile: None,
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            check_globals: true,
+            files: Vec::new(),
+            limits: Limits::default(),
+            statements_executed: 0,
+            output_bytes: 0,
+            deadline: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Statements are only counted as they run. The limits are checked for every loop
+    // iteration and call, including tail calls, since those are the only ways a script can
+    // run for long
+    fn check_limits(&self, line: u32) -> Result<()> {
+        let out_of_statements = matches!(
+            self.limits.max_statements,
+            Some(max) if self.statements_executed > max
+        );
+        let out_of_time = matches!(self.deadline, Some(deadline) if Instant::now() > deadline);
+
+        if out_of_statements || out_of_time {
+            return Err(RuntimeError::at_line(line, "Execution limit exceeded"));
+        }
+        Ok(())
+    }
+
+    // Counts output towards the limit, call it before writing the output
+    pub fn check_output(&mut self, bytes: usize) -> Result<(), String> {
+        self.output_bytes += bytes;
+        match self.limits.max_output_bytes {
+            Some(max) if self.output_bytes > max => Err("Execution limit exceeded".to_owned()),
+            _ => Ok(()),
         }
     }
 
+    // Sets the file that is being run, so imports can be found relative to it
+    pub fn set_script_path(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        self.files = vec![path];
+    }
+
+    // Off in the REPL, where a function may use a global that is declared on a later line
+    pub fn set_check_globals(&mut self, check_globals: bool) {
+        self.check_globals = check_globals;
+    }
+
+    pub fn check_globals(&self) -> bool {
+        self.check_globals
+    }
+
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    // Tail calls don't go through here, so they don't count towards the depth
+    pub fn enter_call(&mut self, token: &Token) -> Result<()> {
+        self.check_limits(token.line)?;
+        if self.call_depth == self.max_call_depth {
+            return Err(RuntimeError::new(token.clone(), "Stack overflow"));
+        }
+        self.call_depth += 1;
+        Ok(())
+    }
+
+    pub fn exit_call(&mut self) {
+        self.call_depth -= 1;
+    }
+
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(HashMap::new());
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    pub fn record_call(&mut self, name: &str, time: Duration) {
+        if let Some(profile) = &mut self.profile {
+            let entry = profile.entry(name.to_owned()).or_default();
+            entry.calls += 1;
+            entry.time += time;
+        }
+    }
+
+    pub fn print_profile(&self) {
+        if let Some(report) = self.profile_report() {
+            eprint!("{}", report);
+        }
+    }
+
+    fn profile_report(&self) -> Option<String> {
+        let profile = self.profile.as_ref()?;
+
+        let mut functions = profile.iter().collect::<Vec<_>>();
+        functions.sort_by_key(|(_, profile)| std::cmp::Reverse(profile.time));
+
+        // The time is inclusive, so it includes the time spent in functions called from it
+        let mut report = String::from("== profile ==\n");
+        report += &format!("{:20} {:>10} {:>12}\n", "function", "calls", "time (ms)");
+        for (name, CallProfile { calls, time }) in functions {
+            report += &format!(
+                "{:20} {:>10} {:>12.3}\n",
+                name,
+                calls,
+                time.as_secs_f64() * 1000.
+            );
+        }
+        Some(report)
+    }
+
+    pub fn is_global(&self, name: &str) -> bool {
+        self.globals.borrow().is_defined(name)
+    }
+
+    pub fn is_constant_global(&self, name: &str) -> bool {
+        self.globals.borrow().is_constant(name)
+    }
+
     pub fn resolve(&mut self, expr_id: usize, depth: usize) {
         self.locals.insert(expr_id, depth);
     }
 
+    // Gives the run that is about to start the full budget of the limits
+    fn reset_budget(&mut self) {
+        self.statements_executed = 0;
+        self.output_bytes = 0;
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
     pub fn interpret(&mut self, statements: &[Stmt]) -> Result<(), RuntimeError> {
+        self.reset_budget();
+
         for stmt in statements {
             self.execute(stmt)?;
         }
         Ok(())
     }
 
+    /// Gets the interpreter ready to run another program after a runtime error, keeping the
+    /// global definitions. `locals` is kept as well, since functions that are still reachable
+    /// from the globals need their resolved variables.
+    pub fn reset(&mut self) {
+        self.environment = self.globals.clone();
+        self.call_depth = 0;
+    }
+
+    pub fn add_warnings(&mut self, warnings: Vec<Diagnostic>) {
+        self.warnings.extend(warnings);
+    }
+
+    /// The warnings found since the last call. They don't stop anything from running, so it is
+    /// up to the caller whether to show them.
+    pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Resolves and evaluates an expression that is not part of any statement
+    pub fn eval_expression(&mut self, expr: &Expr) -> Result<Value, RunError> {
+        let mut errors = ErrorReporter::new();
+        Resolver::new(self, &mut errors).resolve_expr(expr);
+        let (errors, warnings) = errors.into_errors_and_warnings();
+        self.add_warnings(warnings);
+        if !errors.is_empty() {
+            return Err(RunError::ParseError(errors));
+        }
+
+        self.reset_budget();
+        self.evaluate(expr).map_err(RunError::RuntimeError)
+    }
+
+    /// Runs `source` and returns the value it evaluates to. If `source` is not a single
+    /// expression it is run as statements, and the result is `nil`.
+    #[allow(dead_code)]
+    pub fn eval(&mut self, source: &str) -> Result<Value, RunError> {
+        self.reset_budget();
+        let tokens = scan(source)?;
+
+        if let Some(expr) =
+            Parser::new(tokens.clone(), &mut ErrorReporter::new()).parse_expression()
+        {
+            return self.eval_expression(&expr);
+        }
+
+        let statements = parse(tokens, self, false)?;
+        self.interpret(&statements)
+            .map_err(RunError::RuntimeError)?;
+
+        Ok(Value::Nil)
+    }
+
     fn execute(&mut self, stmt: &Stmt) -> Result<()> {
+        self.statements_executed += 1;
+
         match stmt {
             Stmt::Block(statements) => {
                 self.execute_block(
@@ -75,6 +413,7 @@ impl Interpreter {
             Stmt::Class {
                 name,
                 methods,
+                fields,
                 superclass,
             } => {
                 let superclass = match superclass {
@@ -97,6 +436,7 @@ impl Interpreter {
                     .borrow_mut()
                     .define(&name.lexeme, Value::Nil);
 
+                let closure = self.environment.clone();
                 let previous_environment = if let Some(superclass) = &superclass {
                     let previous = self.environment.clone();
                     self.environment = Rc::new(RefCell::new(Environment::new_with_enclosing(
@@ -117,16 +457,17 @@ impl Interpreter {
                             method.name.lexeme.clone(),
                             Rc::new(Function {
                                 closure: self.environment.clone(),
-                                name: method.name.lexeme.clone(),
+                                name: method.name.lexeme.as_str().into(),
                                 body: method.body.clone(),
                                 params: method.params.clone(),
+                                rest: method.rest.clone(),
                                 is_initializer: method.name.lexeme == "init",
                             }),
                         )
                     })
                     .collect::<HashMap<_, _>>();
 
-                let class = Class::new(&name.lexeme, methods, superclass);
+                let class = Class::new(&name.lexeme, methods, fields.clone(), closure, superclass);
 
                 if let Some(previous_environment) = previous_environment {
                     self.environment = previous_environment;
@@ -151,36 +492,201 @@ impl Interpreter {
                 }
             }
             Stmt::Print(expr) => {
-                let value = self.evaluate(expr)?;
+                let value = self.evaluate(expr)?.to_string();
+                self.check_output(value.len() + 1)
+                    .map_err(|message| RuntimeError::at_line(expr.line(), message))?;
                 println!("{}", value);
             }
-            Stmt::Var { name, initializer } => {
+            Stmt::Throw { keyword, value } => {
+                let value = self.evaluate(value)?;
+                Err(RuntimeError::Thrown {
+                    line: keyword.line,
+                    value,
+                })?;
+            }
+            Stmt::Try {
+                body,
+                name,
+                handler,
+            } => {
+                let mut result = self.execute_block(
+                    body,
+                    Rc::new(RefCell::new(Environment::new_with_enclosing(
+                        &self.environment,
+                    ))),
+                );
+                // A tail call made from the body has to run while the handler is still here, so
+                // it is made now instead of by the function being returned from
+                if let Err(RuntimeError::TailCall {
+                    function,
+                    arguments,
+                    named,
+                }) = result
+                {
+                    result = function
+                        .call(self, arguments, named)
+                        .and_then(|value| Err(RuntimeError::Return(value)));
+                }
+                let error = match result {
+                    Err(RuntimeError::Error { message, .. }) => Value::String(message.into()),
+                    Err(RuntimeError::Thrown { value, .. }) => value,
+                    // Returns are errors too, they go on to the function being returned from
+                    result => return result,
+                };
+
+                // A script that ran out of statements or time can't catch its way past the
+                // limit, the error is raised again here
+                self.check_limits(name.line)?;
+
+                let environment = Rc::new(RefCell::new(Environment::new_with_enclosing(
+                    &self.environment,
+                )));
+                environment.borrow_mut().define(&name.lexeme, error);
+                self.execute_block(handler, environment)?;
+            }
+            Stmt::Var {
+                name,
+                initializer,
+                constant,
+            } => {
                 let value = initializer
                     .as_ref()
                     .map(|expr| self.evaluate(expr))
                     .unwrap_or(Ok(Value::Nil))?;
 
-                self.environment.borrow_mut().define(&name.lexeme, value);
+                let mut environment = self.environment.borrow_mut();
+                if *constant {
+                    environment.define_constant(&name.lexeme, value);
+                } else {
+                    environment.define(&name.lexeme, value);
+                }
             }
             Stmt::While { condition, body } => {
                 while is_truthy(&self.evaluate(condition)?) {
+                    self.check_limits(condition.line())?;
                     self.execute(body)?;
                 }
             }
+            Stmt::For {
+                keyword,
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                let environment = Environment::new_with_enclosing(&self.environment);
+                let mut scope = self.enter(Rc::new(RefCell::new(environment)));
+                if let Some(initializer) = initializer {
+                    scope.execute(initializer)?;
+                }
+                while condition.as_ref().map_or(Ok(true), |condition| {
+                    Ok(is_truthy(&scope.evaluate(condition)?))
+                })? {
+                    scope.check_limits(keyword.line)?;
+                    scope.execute(body)?;
+                    if let Some(increment) = increment {
+                        scope.evaluate(increment)?;
+                    }
+                }
+            }
+            Stmt::ForEach {
+                name,
+                iterable,
+                body,
+            } => {
+                let elements = match self.evaluate(iterable)? {
+                    // Iterate over a snapshot, so the body can modify the list
+                    Value::List(list) => list.borrow().clone(),
+                    _ => Err(RuntimeError::at_line(
+                        iterable.line(),
+                        "Can only iterate over lists",
+                    ))?,
+                };
+
+                for element in elements {
+                    self.check_limits(name.line)?;
+                    let mut environment = Environment::new_with_enclosing(&self.environment);
+                    environment.define(&name.lexeme, element);
+                    self.execute_block(
+                        std::slice::from_ref(body),
+                        Rc::new(RefCell::new(environment)),
+                    )?;
+                }
+            }
+            Stmt::Import { keyword, path } => self.import(keyword, path)?,
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                let subject = self.evaluate(subject)?;
+
+                let mut body = default.as_ref();
+                for (value, case_body) in cases {
+                    if is_equal(&subject, &self.evaluate(value)?) {
+                        body = Some(case_body);
+                        break;
+                    }
+                }
+
+                if let Some(body) = body {
+                    self.execute_block(
+                        body,
+                        Rc::new(RefCell::new(Environment::new_with_enclosing(
+                            &self.environment,
+                        ))),
+                    )?;
+                }
+            }
             Stmt::Function(fun) => {
                 let function = Function {
                     closure: self.environment.clone(),
-                    name: fun.name.lexeme.clone(),
+                    name: fun.name.lexeme.as_str().into(),
                     body: fun.body.clone(),
                     params: fun.params.clone(),
+                    rest: fun.rest.clone(),
                     is_initializer: false,
                 };
                 self.environment
                     .borrow_mut()
                     .define(&fun.name.lexeme, Value::Function(Rc::new(function)));
             }
+            Stmt::Return {
+                value:
+                    Some(Expr::Call {
+                        callee,
+                        paren,
+                        arguments,
+                        named,
+                    }),
+                ..
+            } => {
+                let callee = self.evaluate(callee)?;
+                let arguments = self.evaluate_arguments(arguments)?;
+                let named = self.evaluate_named_arguments(named)?;
+
+                match callee {
+                    Value::Function(function) => {
+                        function.check_arguments(paren, arguments.len(), &named)?;
+                        // Tail calls don't go through `enter_call`, so check the limits here
+                        self.check_limits(paren.line)?;
+                        Err(RuntimeError::TailCall {
+                            function,
+                            arguments,
+                            named,
+                        })?;
+                    }
+                    callee => {
+                        let value = callee.call(self, paren, arguments, named)?;
+                        Err(RuntimeError::Return(value))?;
+                    }
+                }
+            }
             Stmt::Return { value, .. } => {
-                let value = self.evaluate(value)?;
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::Nil,
+                };
                 Err(RuntimeError::Return(value))?;
             }
         }
@@ -188,30 +694,93 @@ impl Interpreter {
         Ok(())
     }
 
+    fn import(&mut self, keyword: &Token, name: &str) -> Result<()> {
+        let path = match self.files.last().and_then(|file| file.parent()) {
+            Some(directory) => directory.join(name),
+            None => PathBuf::from(name),
+        };
+
+        let source = std::fs::read_to_string(&path).map_err(|err| {
+            RuntimeError::new(
+                keyword.clone(),
+                format!("Could not import '{}': {}", name, err),
+            )
+        })?;
+
+        let path = path.canonicalize().unwrap_or(path);
+        if self.files.contains(&path) {
+            return Err(RuntimeError::new(
+                keyword.clone(),
+                format!("Cyclic import of '{}'", name),
+            ));
+        }
+
+        // Errors in the imported file are reported with its own line numbers
+        let statements = scan(&source)
+            .and_then(|tokens| parse(tokens, self, false))
+            .map_err(|err| {
+                RuntimeError::new(
+                    keyword.clone(),
+                    format!("Could not import '{}'\n{}", name, err),
+                )
+            })?;
+
+        self.files.push(path);
+        let result = self.execute_block(&statements, self.globals.clone());
+        self.files.pop();
+
+        result
+    }
+
     pub fn execute_block(
         &mut self,
         statements: &[Stmt],
         environment: Rc<RefCell<Environment>>,
     ) -> Result<()> {
-        let previous = self.environment.clone();
-        self.environment = environment;
+        let mut scope = self.enter(environment);
 
         for statement in statements {
-            if let Err(err) = self.execute(statement) {
-                // Poor-mans finally. Should probably use drop in some way
-                self.environment = previous;
-                return Err(err);
-            }
+            // Returns are errors as well, the guard puts the environment back on the way out
+            scope.execute(statement)?;
         }
 
-        self.environment = previous;
-
         Ok(())
     }
 
+    pub fn evaluate_in(
+        &mut self,
+        expr: &Expr,
+        environment: Rc<RefCell<Environment>>,
+    ) -> Result<Value> {
+        self.enter(environment).evaluate(expr)
+    }
+
+    // Runs in `environment` until the guard is dropped, however the scope is left
+    fn enter(&mut self, environment: Rc<RefCell<Environment>>) -> EnvironmentGuard<'_> {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        EnvironmentGuard {
+            interpreter: self,
+            previous: Some(previous),
+        }
+    }
+
+    fn evaluate_arguments(&mut self, arguments: &[Expr]) -> Result<Vec<Value>> {
+        arguments.iter().map(|arg| self.evaluate(arg)).collect()
+    }
+
+    fn evaluate_named_arguments(
+        &mut self,
+        arguments: &[(Token, Expr)],
+    ) -> Result<Vec<(Token, Value)>> {
+        arguments
+            .iter()
+            .map(|(name, arg)| Ok((name.clone(), self.evaluate(arg)?)))
+            .collect()
+    }
+
     fn evaluate(&mut self, expr: &Expr) -> Result<Value> {
         Ok(match expr {
-            Expr::Literal(literal) => match literal {
+            Expr::Literal { value, .. } => match value {
                 Literal::Bool(value) => Value::Bool(*value),
                 Literal::String(value) => Value::String(value.clone()),
                 Literal::Number(value) => Value::Number(*value),
@@ -227,22 +796,22 @@ impl Interpreter {
 
                 use TokenType::*;
 
-                match (left, right) {
-                    (Value::String(left), Value::String(right)) => match &operator.typ {
-                        Plus => Value::String(left + &right),
+                match (&operator.typ, left, right) {
+                    (EqualEqual, left, right) => Value::Bool(is_equal(&left, &right)),
+                    (BangEqual, left, right) => Value::Bool(!is_equal(&left, &right)),
+                    (_, Value::String(left), Value::String(right)) => match &operator.typ {
+                        Plus => Value::String(format!("{}{}", left, right).into()),
                         Greater => Value::Bool(left > right),
                         GreaterEqual => Value::Bool(left >= right),
                         Less => Value::Bool(left < right),
                         LessEqual => Value::Bool(left <= right),
-                        BangEqual => Value::Bool(left != right),
-                        EqualEqual => Value::Bool(left == right),
 
                         _ => Err(RuntimeError::new(
                             operator.clone(),
                             "I can't do that operation on two strings".to_owned(),
                         ))?,
                     },
-                    (Value::Number(left), Value::Number(right)) => match &operator.typ {
+                    (_, Value::Number(left), Value::Number(right)) => match &operator.typ {
                         Plus => Value::Number(left + right),
                         Minus => Value::Number(left - right),
                         Star => Value::Number(left * right),
@@ -251,50 +820,62 @@ impl Interpreter {
                         GreaterEqual => Value::Bool(left >= right),
                         Less => Value::Bool(left < right),
                         LessEqual => Value::Bool(left <= right),
-                        BangEqual => Value::Bool(left != right),
-                        EqualEqual => Value::Bool(left == right),
 
                         _ => Err(RuntimeError::new(
                             operator.clone(),
                             "I can't do that operation on two numbers",
                         ))?,
                     },
-                    (Value::Bool(left), Value::Bool(right)) => match &operator.typ {
-                        BangEqual => Value::Bool(left != right),
-                        EqualEqual => Value::Bool(left == right),
-
-                        _ => Err(RuntimeError::new(
-                            operator.clone(),
-                            "I can't do that operation on two booleans",
-                        ))?,
-                    },
-                    (Value::Nil, Value::Nil) => match &operator.typ {
-                        BangEqual => Value::Bool(false),
-                        EqualEqual => Value::Bool(true),
-                        _ => Err(RuntimeError::new(
-                            operator.clone(),
-                            "I can't do that operation on two 'NIL'",
-                        ))?,
-                    },
-                    _ => match &operator.typ {
-                        BangEqual => Value::Bool(true),
-                        EqualEqual => Value::Bool(false),
-                        _ => Err(RuntimeError::new(
-                            operator.clone(),
-                            "I can't do that operation on two values with different type",
-                        ))?,
-                    },
+                    (_, Value::Bool(_), Value::Bool(_)) => Err(RuntimeError::new(
+                        operator.clone(),
+                        "I can't do that operation on two booleans",
+                    ))?,
+                    (_, Value::Nil, Value::Nil) => Err(RuntimeError::new(
+                        operator.clone(),
+                        "I can't do that operation on two 'NIL'",
+                    ))?,
+                    (Star, Value::String(string), count @ Value::Number(_))
+                    | (Star, count @ Value::Number(_), Value::String(string)) => {
+                        let count = count.as_index().ok_or_else(|| {
+                            RuntimeError::new(
+                                operator.clone(),
+                                "Can only repeat a string a non-negative whole number of times",
+                            )
+                        })?;
+                        match string.len().checked_mul(count) {
+                            Some(length) if length <= MAX_REPEATED_STRING_LENGTH => {
+                                Value::String(string.repeat(count).into())
+                            }
+                            _ => Err(RuntimeError::new(
+                                operator.clone(),
+                                "Repeated string is too long",
+                            ))?,
+                        }
+                    }
+                    // Mixing numbers and strings is an error rather than converting the number,
+                    // so `"1" + 1` doesn't silently become "11"
+                    (Plus, _, _) => Err(RuntimeError::new(
+                        operator.clone(),
+                        "Operands must be two numbers or two strings",
+                    ))?,
+                    _ => Err(RuntimeError::new(
+                        operator.clone(),
+                        "I can't do that operation on two values with different type",
+                    ))?,
                 }
             }
-            Expr::Grouping(expr) => self.evaluate(expr)?,
+            Expr::Grouping { expression, .. } => self.evaluate(expression)?,
+            Expr::Stringify(expression) => match self.evaluate(expression)? {
+                Value::String(string) => Value::String(string),
+                value => Value::String(value.to_string().into()),
+            },
             Expr::Unary { operator, right } => {
+                let line = right.line();
                 let right = self.evaluate(&right)?;
                 match operator.typ {
                     TokenType::Minus => match right {
                         Value::Number(value) => Value::Number(-value),
-                        _ => {
-                            panic!("Tried to use unary operator on something that is not a number")
-                        }
+                        _ => Err(RuntimeError::at_line(line, "Operand must be a number"))?,
                     },
                     TokenType::Bang => Value::Bool(!is_truthy(&right)),
                     _ => panic!("Invalid type for unary -, {}", operator),
@@ -327,8 +908,8 @@ impl Interpreter {
             } => {
                 let left = self.evaluate(left)?;
                 let return_left = match operator.typ {
-                    TokenType::Or => is_truthy(&left),
-                    TokenType::And => !is_truthy(&left),
+                    TokenType::Or | TokenType::PipePipe => is_truthy(&left),
+                    TokenType::And | TokenType::AmpAmp => !is_truthy(&left),
                     _ => panic!("Invalid operator in Logical, {:?}", operator),
                 };
                 if return_left {
@@ -341,15 +922,13 @@ impl Interpreter {
                 callee,
                 paren,
                 arguments,
+                named,
             } => {
                 let callee = self.evaluate(callee)?;
+                let arguments = self.evaluate_arguments(arguments)?;
+                let named = self.evaluate_named_arguments(named)?;
 
-                let arguments = arguments
-                    .iter()
-                    .map(|arg| self.evaluate(arg))
-                    .collect::<Result<Vec<_>>>()?;
-
-                callee.call(self, paren, arguments)?
+                callee.call(self, paren, arguments, named)?
             }
             Expr::Get { object, name } => {
                 let object = self.evaluate(object)?;
@@ -379,6 +958,93 @@ impl Interpreter {
                     ))?,
                 }
             }
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                match object {
+                    Value::List(list) => {
+                        let list = list.borrow();
+                        list[list_index(&list, &index, bracket)?].clone()
+                    }
+                    Value::Instance(instance) => {
+                        instance.get_named(&field_name(&index, bracket)?, bracket)?
+                    }
+                    _ => Err(RuntimeError::new(
+                        bracket.clone(),
+                        "Only lists and instances can be indexed",
+                    ))?,
+                }
+            }
+            Expr::Slice {
+                object,
+                bracket,
+                start,
+                end,
+            } => {
+                let object = self.evaluate(object)?;
+                let start = start
+                    .as_ref()
+                    .map(|start| self.evaluate(start))
+                    .transpose()?;
+                let end = end.as_ref().map(|end| self.evaluate(end)).transpose()?;
+                match object {
+                    Value::List(list) => {
+                        let list = list.borrow();
+                        let start = slice_bound(&list, start, 0, bracket)?;
+                        let end = slice_bound(&list, end, list.len(), bracket)?;
+                        if start > end {
+                            Err(RuntimeError::new(
+                                bracket.clone(),
+                                format!("Slice start {} is after its end {}", start, end),
+                            ))?
+                        }
+                        Value::List(Rc::new(RefCell::new(list[start..end].to_vec())))
+                    }
+                    _ => Err(RuntimeError::new(
+                        bracket.clone(),
+                        "Only lists can be sliced",
+                    ))?,
+                }
+            }
+            Expr::IndexSet {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                match object {
+                    Value::List(list) => {
+                        let value = self.evaluate(value)?;
+                        let mut list = list.borrow_mut();
+                        let index = list_index(&list, &index, bracket)?;
+                        list[index] = value.clone();
+                        value
+                    }
+                    Value::Instance(instance) => {
+                        let name = field_name(&index, bracket)?;
+                        let value = self.evaluate(value)?;
+                        instance.set_named(&name, value.clone());
+                        value
+                    }
+                    _ => Err(RuntimeError::new(
+                        bracket.clone(),
+                        "Only lists and instances can be indexed",
+                    ))?,
+                }
+            }
+            Expr::List { elements, .. } => {
+                let elements = elements
+                    .iter()
+                    .map(|element| self.evaluate(element))
+                    .collect::<Result<Vec<_>>>()?;
+                Value::List(Rc::new(RefCell::new(elements)))
+            }
             Expr::This { keyword, expr_id } => self.lookup_variable(keyword, *expr_id)?,
             Expr::Super {
                 keyword,
@@ -418,6 +1084,188 @@ impl Interpreter {
     }
 }
 
+fn list_index(list: &[Value], index: &Value, bracket: &Token) -> Result<usize> {
+    match index.as_index() {
+        Some(index) if index < list.len() => Ok(index),
+        Some(index) => Err(RuntimeError::new(
+            bracket.clone(),
+            format!("List index {} out of range", index),
+        )),
+        None => Err(RuntimeError::new(
+            bracket.clone(),
+            "List index must be a non-negative whole number",
+        )),
+    }
+}
+
+// A bound in `list[start:end]`, `default` when it is left out. Negative bounds count from the
+// end. Bounds outside the list are an error, like indexing, instead of being clamped
+fn slice_bound(
+    list: &[Value],
+    bound: Option<Value>,
+    default: usize,
+    bracket: &Token,
+) -> Result<usize> {
+    let (bound, from_end) = match bound {
+        None => return Ok(default),
+        Some(Value::Number(bound)) if bound < 0. => (bound, true),
+        Some(Value::Number(bound)) => (bound, false),
+        Some(_) => Err(RuntimeError::new(
+            bracket.clone(),
+            "Slice bounds must be whole numbers",
+        ))?,
+    };
+
+    let offset = Value::Number(bound.abs())
+        .as_index()
+        .ok_or_else(|| RuntimeError::new(bracket.clone(), "Slice bounds must be whole numbers"))?;
+    let index = if from_end {
+        list.len().checked_sub(offset)
+    } else {
+        Some(offset).filter(|index| *index <= list.len())
+    };
+    index.ok_or_else(|| {
+        RuntimeError::new(
+            bracket.clone(),
+            format!("Slice bound {} out of range", bound),
+        )
+    })
+}
+
+// The name of the field in `instance[name]`
+fn field_name(index: &Value, bracket: &Token) -> Result<Rc<str>> {
+    match index {
+        Value::String(name) => Ok(name.clone()),
+        _ => Err(RuntimeError::new(
+            bracket.clone(),
+            "Instance fields can only be indexed with a string",
+        )),
+    }
+}
+
+// Blocks for the given number of milliseconds
+fn sleep(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments[0] {
+        Value::Number(millis) if millis >= 0. && millis.is_finite() => {
+            std::thread::sleep(Duration::from_secs_f64(millis / 1000.));
+            Ok(Value::Nil)
+        }
+        _ => Err("sleep expects a non-negative number of milliseconds".to_owned()),
+    }
+}
+
+// The tolerance `approxEq` uses when it isn't given one
+const DEFAULT_EPSILON: f64 = 1e-9;
+
+// `approxEq(a, b)` or `approxEq(a, b, epsilon)`. Whether two numbers are within epsilon of
+// each other, since `==` is exact and `0.1 + 0.2 == 0.3` is false
+fn approx_eq(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments[..] {
+        [Value::Number(a), Value::Number(b)] => Ok(Value::Bool((a - b).abs() <= DEFAULT_EPSILON)),
+        [Value::Number(a), Value::Number(b), Value::Number(epsilon)] if epsilon >= 0. => {
+            Ok(Value::Bool((a - b).abs() <= epsilon))
+        }
+        _ => Err("approxEq expects two numbers and an optional non-negative epsilon".to_owned()),
+    }
+}
+
+// `==` can't find NaN, since NaN isn't equal to anything, not even itself
+fn is_nan(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments[..] {
+        [Value::Number(number)] => Ok(Value::Bool(number.is_nan())),
+        _ => Err("isNaN expects a number".to_owned()),
+    }
+}
+
+// False for NaN and both infinities
+fn is_finite(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    match arguments[..] {
+        [Value::Number(number)] => Ok(Value::Bool(number.is_finite())),
+        _ => Err("isFinite expects a number".to_owned()),
+    }
+}
+
+// `clone(value)` copies a list or an instance, but not the lists and instances inside it.
+// `clone(value, true)` copies those too, all the way down
+fn clone(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    let deep = arguments.get(1).is_some_and(is_truthy);
+    Ok(arguments[0].copy(deep))
+}
+
+// The names of the fields currently set on an instance, as a list of strings
+fn fields(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    match &arguments[0] {
+        Value::Instance(instance) => Ok(Value::List(Rc::new(RefCell::new(
+            instance
+                .field_names()
+                .into_iter()
+                .map(|name| Value::String(name.into()))
+                .collect(),
+        )))),
+        _ => Err("fields expects an instance".to_owned()),
+    }
+}
+
+// Fails with the message, or a default one, unless the condition is truthy
+fn assert(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    if is_truthy(&arguments[0]) {
+        return Ok(Value::Nil);
+    }
+
+    match arguments.get(1) {
+        Some(message) => Err(format!("Assertion failed: {}", message)),
+        None => Err("Assertion failed".to_owned()),
+    }
+}
+
+// Like `print`, but without the newline
+fn write(interpreter: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    let value = arguments[0].to_string();
+    interpreter.check_output(value.len())?;
+    print!("{}", value);
+    Ok(Value::Nil)
+}
+
+// Splits a string on a separator. An empty separator splits the string into characters
+fn split(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    let (string, separator) = match (&arguments[0], &arguments[1]) {
+        (Value::String(string), Value::String(separator)) => (string, separator),
+        _ => return Err("split expects a string and a string separator".to_owned()),
+    };
+
+    let parts = if separator.is_empty() {
+        string
+            .chars()
+            .map(|c| Value::String(c.to_string().into()))
+            .collect()
+    } else {
+        string
+            .split(separator.as_ref())
+            .map(|part| Value::String(part.into()))
+            .collect()
+    };
+
+    Ok(Value::List(Rc::new(RefCell::new(parts))))
+}
+
+// Joins the elements of a list with a separator. Elements that are not strings are joined
+// the same way as `print` would show them
+fn join(_: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String> {
+    let (list, separator) = match (&arguments[0], &arguments[1]) {
+        (Value::List(list), Value::String(separator)) => (list, separator),
+        _ => return Err("join expects a list and a string separator".to_owned()),
+    };
+
+    let joined = list
+        .borrow()
+        .iter()
+        .map(|element| element.to_string())
+        .collect::<Vec<_>>()
+        .join(separator);
+
+    Ok(Value::String(joined.into()))
+}
+
 fn is_truthy(value: &Value) -> bool {
     match value {
         Value::Bool(value) => *value,
@@ -425,3 +1273,163 @@ fn is_truthy(value: &Value) -> bool {
         _ => true,
     }
 }
+
+// The semantics of `==`. Values of different types are never equal
+// Numbers compare like IEEE 754 floats: NaN is not equal to itself, and 0 and -0 are equal. Use
+// `isNaN` to check for NaN. Lists, instances, functions and classes are only equal to themselves
+fn is_equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::String(left), Value::String(right)) => left == right,
+        (Value::Number(left), Value::Number(right)) => left == right,
+        (Value::Bool(left), Value::Bool(right)) => left == right,
+        (Value::Nil, Value::Nil) => true,
+        (Value::List(left), Value::List(right)) => Rc::ptr_eq(left, right),
+        (Value::Instance(left), Value::Instance(right)) => Rc::ptr_eq(left, right),
+        (Value::Function(left), Value::Function(right)) => Rc::ptr_eq(left, right),
+        (Value::Class(left), Value::Class(right)) => Rc::ptr_eq(left, right),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_number(value: Value, expected: f64) {
+        match value {
+            Value::Number(number) => assert_eq!(number, expected),
+            value => panic!("Expected {}, got {}", expected, value),
+        }
+    }
+
+    #[test]
+    fn eval_returns_the_value() {
+        let mut interpreter = Interpreter::new();
+        assert_number(interpreter.eval("2 * 21").unwrap(), 42.);
+    }
+
+    #[test]
+    fn eval_returns_runtime_errors() {
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(
+            interpreter.eval("-\"text\""),
+            Err(RunError::RuntimeError(_))
+        ));
+    }
+
+    #[test]
+    fn reset_after_a_runtime_error_keeps_the_globals() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.eval("var kept = 1; fun fail() { return -\"text\"; } { fail(); }");
+        assert!(matches!(result, Err(RunError::RuntimeError(_))));
+
+        interpreter.reset();
+        interpreter.eval("var total = kept + 1;").unwrap();
+        assert_number(interpreter.eval("total").unwrap(), 2.);
+    }
+
+    #[test]
+    fn profile_reports_the_call_count() {
+        let mut interpreter = Interpreter::new();
+        interpreter.enable_profiling();
+        interpreter
+            .eval("fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } fib(10);")
+            .unwrap();
+
+        let report = interpreter.profile_report().unwrap();
+        let fib = report
+            .lines()
+            .find(|line| line.starts_with("fib "))
+            .expect("fib is in the profile");
+        assert_eq!(fib.split_whitespace().nth(1), Some("177"));
+    }
+
+    #[test]
+    fn eval_collects_warnings() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval("var a = 1;\na;").unwrap();
+
+        let warnings = interpreter.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "[line 2] Warning: Expression result is unused"
+        );
+        assert!(interpreter.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn profile_counts_tail_calls() {
+        let mut interpreter = Interpreter::new();
+        interpreter.enable_profiling();
+        interpreter
+            .eval(
+                "fun count(n) { if (n <= 0) return 0; return count(n - 1); } \
+                 fun helper(n) { return n; } \
+                 fun outer(n) { return helper(n); } \
+                 count(5); outer(1);",
+            )
+            .unwrap();
+
+        let report = interpreter.profile_report().unwrap();
+        let calls = |name: &str| {
+            report
+                .lines()
+                .find(|line| line.starts_with(&format!("{} ", name)))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .map(str::to_owned)
+        };
+        assert_eq!(calls("count").as_deref(), Some("6"));
+        assert_eq!(calls("outer").as_deref(), Some("1"));
+        assert_eq!(calls("helper").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn each_eval_gets_a_new_statement_budget() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits {
+            max_statements: Some(5),
+            ..Limits::default()
+        });
+        interpreter.eval("fun f() { return 1; }").unwrap();
+
+        for _ in 0..10 {
+            assert_number(interpreter.eval("f()").unwrap(), 1.);
+        }
+    }
+
+    #[test]
+    fn each_eval_gets_a_new_deadline() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits {
+            timeout: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        });
+        interpreter.eval("fun f() { return 1; }").unwrap();
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_number(interpreter.eval("f()").unwrap(), 1.);
+    }
+
+    // Like the REPL, where every line is resolved on its own
+    #[test]
+    fn constants_from_an_earlier_eval_cannot_be_assigned() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval("const Z = 1;").unwrap();
+
+        assert!(matches!(
+            interpreter.eval("Z = 2;"),
+            Err(RunError::ParseError(_))
+        ));
+        assert!(matches!(
+            interpreter.eval("Z = 2"),
+            Err(RunError::ParseError(_))
+        ));
+        assert_number(interpreter.eval("Z").unwrap(), 1.);
+
+        // Declaring it again as a variable replaces the constant
+        interpreter.eval("var Z = 3;").unwrap();
+        interpreter.eval("Z = 4;").unwrap();
+        assert_number(interpreter.eval("Z").unwrap(), 4.);
+    }
+}
@@ -1,25 +1,42 @@
-use std::io::prelude::*;
+use std::{io::prelude::*, path::Path, str::FromStr, time::Duration};
 
 use anyhow::Result;
 
+use ast::Expr;
 use error_reporter::ErrorReporter;
-use interpreter::Interpreter;
+use interpreter::{Interpreter, Limits};
 use parser::Parser;
+use pipeline::{parse, scan, RunError};
 use resolver::Resolver;
-use runtime_error::RuntimeError;
+use token::{Token, TokenType};
+use value::Value;
 
 mod ast;
 mod environment;
 mod error_reporter;
+mod formatter;
 mod interpreter;
 mod parser;
+mod pipeline;
 mod resolver;
 mod runtime_error;
 mod scanner;
 mod token;
 mod value;
 
+// The interpreter recurses on the Rust stack for every Lox call, and debug builds use a lot of
+// stack per call, so give it more room than the main thread has
+const INTERPRETER_STACK_SIZE: usize = 256 * 1024 * 1024;
+
 fn main() -> Result<()> {
+    std::thread::Builder::new()
+        .stack_size(INTERPRETER_STACK_SIZE)
+        .spawn(run_main)?
+        .join()
+        .expect("Interpreter thread panicked")
+}
+
+fn run_main() -> Result<()> {
     // let expr = ast::Expr::Binary {
     //     left: Box::new(ast::Expr::Unary {
     //         operator: token::Token::new(token::TokenType::Minus, "-".to_owned(), 1),
@@ -33,16 +50,58 @@ fn main() -> Result<()> {
 
     // println!("{}", expr);
 
-    let args = std::env::args().collect::<Vec<_>>();
+    let mut args = std::env::args().collect::<Vec<_>>();
+
+    let mut lox = Lox::new();
+    if let Some(index) = args.iter().position(|arg| arg == "--time") {
+        args.remove(index);
+        lox.interpreter.enable_profiling();
+    }
+    if let Some(depth) = take_flag(&mut args, "--max-call-depth") {
+        lox.interpreter.set_max_call_depth(depth);
+    }
+    lox.interpreter.set_limits(Limits {
+        max_statements: take_flag(&mut args, "--max-statements"),
+        max_output_bytes: take_flag(&mut args, "--max-output"),
+        timeout: take_flag(&mut args, "--timeout").map(Duration::from_millis),
+    });
+    let definition: Option<String> = take_flag(&mut args, "--definition");
+    let format = match args.iter().position(|arg| arg == "--fmt") {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
 
     if args.len() > 2 {
-        eprintln!("Usage: lox-treewalker [script]");
+        eprintln!(
+            "Usage: lox-treewalker [--time] [--max-call-depth=N] [--max-statements=N] \
+             [--max-output=BYTES] [--timeout=MS] [--definition=LINE:NAME] [--fmt] [script]\n\n\
+             --fmt prints the script formatted instead of running it. Comments are not kept"
+        );
         Ok(())
+    } else if let (2, Some(position)) = (args.len(), definition) {
+        lox.find_definition(&args[1], &position)
+    } else if args.len() == 2 && format {
+        lox.format_file(&args[1])
     } else if args.len() == 2 {
-        Lox::new().run_file(&args[1])
+        lox.run_file(&args[1])
     } else {
-        Lox::new().run_prompt()
+        lox.run_prompt()
+    }
+}
+
+// Removes a `--name=value` flag from the arguments and parses its value
+fn take_flag<T: FromStr>(args: &mut Vec<String>, name: &str) -> Option<T> {
+    let prefix = format!("{}=", name);
+    let index = args.iter().position(|arg| arg.starts_with(&prefix))?;
+    let arg = args.remove(index);
+    let value = arg[prefix.len()..].parse().ok();
+    if value.is_none() {
+        eprintln!("Invalid {}, ignoring it", name);
     }
+    value
 }
 
 struct Lox {
@@ -57,32 +116,87 @@ impl Lox {
     }
 
     fn run_file(&mut self, name: &str) -> Result<()> {
+        self.interpreter.set_script_path(Path::new(name));
         let mut file = std::fs::File::open(name)?;
         let mut buffer = String::new();
         file.read_to_string(&mut buffer)?;
 
-        let mut errors = ErrorReporter { had_error: false };
-
-        let result = self.run(&buffer, &mut errors);
+        let result = self.run(&buffer, false);
+        self.interpreter.print_profile();
 
-        match result {
-            Ok(()) => {}
-            Err(RunError::ParseError) => {
-                std::process::exit(65);
+        if let Err(err) = result {
+            // Printed the same way as in the REPL
+            println!("{}", err);
+            match err {
+                RunError::ParseError(_) | RunError::TokenizeError(_) => std::process::exit(65),
+                RunError::RuntimeError(_) => std::process::exit(70),
             }
-            Err(RunError::TokenizeError) => {
+        }
+
+        Ok(())
+    }
+
+    // Prints where the variable named at `position`, like `12:count`, is declared, without
+    // running the script
+    fn find_definition(&mut self, name: &str, position: &str) -> Result<()> {
+        let (line, variable) = match position.split_once(':') {
+            Some((line, variable)) => (line.parse()?, variable),
+            None => anyhow::bail!("Expected the position as LINE:NAME"),
+        };
+
+        let source = std::fs::read_to_string(name)?;
+        let tokens = match scan(&source) {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                println!("{}", err);
                 std::process::exit(65);
             }
-            Err(RunError::RuntimeError(error)) => {
-                println!("{}", error);
-                std::process::exit(70);
-            }
+        };
+        // Errors don't matter here, whatever could be parsed can still be looked up
+        let mut errors = ErrorReporter::new();
+        let statements = Parser::new(tokens, &mut errors).parse();
+        let mut resolver = Resolver::new(&mut self.interpreter, &mut errors);
+        resolver.resolve(&statements);
+
+        match resolver.definition_at(line, variable) {
+            Some(token) => println!("'{}' is declared on line {}", variable, token.line),
+            None => println!("No local definition of '{}' on line {}", variable, line),
         }
 
         Ok(())
     }
 
+    // Prints the script formatted, without running it
+    fn format_file(&mut self, name: &str) -> Result<()> {
+        let source = std::fs::read_to_string(name)?;
+        let mut errors = ErrorReporter::new();
+        let mut scanner = scanner::Scanner::new(&source, &mut errors);
+        let tokens = scanner.scan_tokens();
+        if scanner.had_comments() {
+            eprintln!(
+                "Warning: {} has comments, they are not kept when formatting",
+                name
+            );
+        }
+        if errors.had_error() {
+            println!("{}", RunError::TokenizeError(errors.diagnostics));
+            std::process::exit(65);
+        }
+
+        let mut errors = ErrorReporter::new();
+        let statements = Parser::new(tokens, &mut errors).parse();
+        if errors.had_error() {
+            println!("{}", RunError::ParseError(errors.diagnostics));
+            std::process::exit(65);
+        }
+
+        print!("{}", formatter::format_source(&statements));
+        Ok(())
+    }
+
     fn run_prompt(&mut self) -> Result<()> {
+        self.interpreter.set_check_globals(false);
+
         let mut buffer = String::new();
         let mut stdout = std::io::stdout();
         let stdin = std::io::stdin();
@@ -95,68 +209,61 @@ impl Lox {
             if buffer.is_empty() {
                 break;
             }
-            let mut errors = ErrorReporter { had_error: false };
-            if let Err(err) = self.run(&buffer, &mut errors) {
+            if let Err(err) = self.run(&buffer, true) {
                 // If the user makes a mistake, it shouldn’t kill their entire session:
                 println!("{}", err);
+                self.interpreter.reset();
             }
-            // If the user makes a mistake, it shouldn’t kill their entire session:
-            errors.had_error = false;
         }
 
+        self.interpreter.print_profile();
+
         Ok(())
     }
 
-    fn run(&mut self, source: &str, errors: &mut ErrorReporter) -> Result<(), RunError> {
-        let mut scanner = scanner::Scanner::new(source, errors);
-        let tokens = scanner.scan_tokens();
-
-        if errors.had_error {
-            return Err(RunError::TokenizeError);
-        }
-
-        let parser = Parser::new(tokens);
-        let statements = parser.parse();
+    // In the REPL, a line that is a single expression has its value printed
+    fn run(&mut self, source: &str, repl: bool) -> Result<(), RunError> {
+        let tokens = scan(source)?;
 
-        if errors.had_error {
-            return Err(RunError::ParseError);
-        }
-
-        match statements {
-            Some(statements) => {
-                let mut resolver = Resolver::new(&mut self.interpreter, errors);
-                resolver.resolve(&statements);
-
-                if errors.had_error {
-                    return Err(RunError::ParseError);
+        if repl {
+            if let Some(expr) = repl_expression(&tokens) {
+                let value = self.interpreter.eval_expression(&expr);
+                self.print_warnings();
+                let value = value?;
+                if !matches!(value, Value::Nil) {
+                    println!("{}", value);
                 }
-
-                self.interpreter
-                    .interpret(&statements)
-                    .map_err(|err| RunError::RuntimeError(err))?;
+                return Ok(());
             }
-            None => return Err(RunError::ParseError),
         }
 
-        Ok(())
-    }
-}
+        let statements = parse(tokens, &mut self.interpreter, repl);
+        self.print_warnings();
 
-#[derive(Debug)]
-enum RunError {
-    TokenizeError,
-    ParseError,
-    RuntimeError(RuntimeError),
-}
+        let result = self
+            .interpreter
+            .interpret(&statements?)
+            .map_err(|err| RunError::RuntimeError(err));
+        // Imported files are resolved while running
+        self.print_warnings();
+        result
+    }
 
-impl std::fmt::Display for RunError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            RunError::TokenizeError => write!(f, "Error tokenizing"),
-            RunError::ParseError => write!(f, "Error parsing"),
-            RunError::RuntimeError(inner) => write!(f, "{}", inner),
+    // Warnings go to stderr, so they don't mix with the program's output
+    fn print_warnings(&mut self) {
+        for warning in self.interpreter.take_warnings() {
+            eprintln!("{}", warning);
         }
     }
 }
 
-impl std::error::Error for RunError {}
+// `1 + 2` or `1 + 2;`. Nil values aren't printed, so calling a function for its side effects
+// doesn't add noise
+fn repl_expression(tokens: &[Token]) -> Option<Expr> {
+    let mut tokens = tokens.to_vec();
+    if tokens.len() >= 2 && tokens[tokens.len() - 2].typ == TokenType::Semicolon {
+        tokens.remove(tokens.len() - 2);
+    }
+    // Errors aren't reported, the line is run as statements instead
+    Parser::new(tokens, &mut ErrorReporter::new()).parse_expression()
+}
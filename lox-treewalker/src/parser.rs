@@ -1,6 +1,6 @@
 use crate::{
-    ast::{Expr, Literal, Stmt, StmtFunction, VariableExpr},
-    error_reporter::format_err,
+    ast::{Expr, Field, Literal, Param, Stmt, StmtFunction, VariableExpr},
+    error_reporter::{ErrorReporter, Location},
     token::{Token, TokenType},
 };
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -11,9 +11,12 @@ fn next_expr_id() -> usize {
     EXPR_COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
-pub struct Parser {
+pub struct Parser<'a> {
     tokens: Vec<Token>,
     current: usize,
+    errors: &'a mut ErrorReporter,
+    // Whether the `;` after the last statement in a block or the input can be left out
+    lenient: bool,
 }
 
 #[derive(Debug)]
@@ -33,45 +36,30 @@ impl ParseError {
 
 type Result<T> = std::result::Result<T, ParseError>;
 
-impl std::fmt::Display for ParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.token.typ == TokenType::EOF {
-            write!(
-                f,
-                "{}",
-                format_err(self.token.line, " at end", &self.message)
-            )
-        } else {
-            write!(
-                f,
-                "{}",
-                format_err(
-                    self.token.line,
-                    &format!(" at '{}'", self.token.lexeme),
-                    &self.message
-                )
-            )
+impl<'a> Parser<'a> {
+    pub fn new(tokens: Vec<Token>, errors: &'a mut ErrorReporter) -> Parser<'a> {
+        Parser {
+            tokens,
+            current: 0,
+            errors,
+            lenient: false,
         }
     }
-}
-
-impl std::error::Error for ParseError {}
 
-impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+    // Used in the REPL, where a forgotten `;` at the end of the line is easy to do
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
     }
 
-    pub fn parse(mut self) -> Option<Vec<Stmt>> {
+    // Errors are added to the `ErrorReporter`, the statements are only usable if there are none
+    pub fn parse(mut self) -> Vec<Stmt> {
         let mut statements = Vec::new();
-        let mut had_error = false;
 
         while !self.is_at_end() {
             match self.declaration() {
                 Ok(statement) => statements.push(statement),
                 Err(err) => {
-                    had_error = true;
-                    println!("{}", err);
+                    self.report(err);
                     self.synchronize();
                     if self.is_at_end() {
                         break;
@@ -80,10 +68,28 @@ impl Parser {
             }
         }
 
-        if had_error {
-            None
+        statements
+    }
+
+    fn report(&mut self, error: ParseError) {
+        let location = if error.token.typ == TokenType::EOF {
+            Location::End
+        } else {
+            Location::Token(error.token.lexeme.clone())
+        };
+        self.errors
+            .report(error.token.line, Some(location), error.message);
+    }
+
+    // Parses the tokens as a single expression. Unlike `parse` this does not report
+    // errors, so the caller can fall back to parsing statements instead
+    pub fn parse_expression(mut self) -> Option<Expr> {
+        let expr = self.expression().ok()?;
+
+        if self.is_at_end() {
+            Some(expr)
         } else {
-            Some(statements)
+            None
         }
     }
 
@@ -92,6 +98,8 @@ impl Parser {
     fn declaration(&mut self) -> Result<Stmt> {
         if self.match_token(TokenType::Var) {
             self.var_declaration()
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration()
         } else if self.match_token(TokenType::Class) {
             self.class_declaration()
         } else if self.match_token(TokenType::Fun) {
@@ -117,14 +125,25 @@ impl Parser {
         self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
 
         let mut methods = Vec::new();
+        let mut fields = Vec::new();
         while !self.check(TokenType::RightBrace) && !self.is_at_end() {
-            methods.push(self.function("method")?);
+            if self.match_token(TokenType::Var) {
+                if let Stmt::Var {
+                    name, initializer, ..
+                } = self.var_declaration()?
+                {
+                    fields.push(Field { name, initializer });
+                }
+            } else {
+                methods.push(self.function("method")?);
+            }
         }
         self.consume(TokenType::RightBrace, "Expect '}' after class body")?;
 
         Ok(Stmt::Class {
             name,
             methods,
+            fields,
             superclass,
         })
     }
@@ -136,10 +155,43 @@ impl Parser {
             format!("Expect '(' after {} name.", kind),
         )?;
 
-        let mut params = Vec::new();
+        let mut params: Vec<Param> = Vec::new();
+        let mut rest = None;
         if !self.check(TokenType::RightParen) {
             loop {
-                params.push(self.consume(TokenType::Identifier, "Expect paramater name")?);
+                let name = self.consume(TokenType::Identifier, "Expect paramater name")?;
+                if self.match_token(TokenType::DotDotDot) {
+                    rest = Some(name);
+                    if self.check(TokenType::Comma) {
+                        return Err(ParseError::new(
+                            self.peek().clone(),
+                            "Rest parameter must be the last parameter",
+                        ));
+                    }
+                    break;
+                }
+
+                let default = if self.match_token(TokenType::Equal) {
+                    Some(self.expression()?)
+                } else {
+                    None
+                };
+
+                let follows_default = matches!(
+                    params.last(),
+                    Some(Param {
+                        default: Some(_),
+                        ..
+                    })
+                );
+                if default.is_none() && follows_default {
+                    return Err(ParseError::new(
+                        name,
+                        "Parameter without a default value cannot follow one with a default",
+                    ));
+                }
+
+                params.push(Param { name, default });
 
                 if !self.match_token(TokenType::Comma) {
                     break;
@@ -153,7 +205,12 @@ impl Parser {
         )?;
         let body = self.block()?;
 
-        Ok(StmtFunction { name, params, body })
+        Ok(StmtFunction {
+            name,
+            params: params.into(),
+            rest,
+            body: body.into(),
+        })
     }
 
     fn var_declaration(&mut self) -> Result<Stmt> {
@@ -165,11 +222,25 @@ impl Parser {
             None
         };
 
-        self.consume(
-            TokenType::Semicolon,
-            "Expect ';' after variable declaration.",
-        )?;
-        Ok(Stmt::Var { name, initializer })
+        self.end_statement("Expect ';' after variable declaration.")?;
+        Ok(Stmt::Var {
+            name,
+            initializer,
+            constant: false,
+        })
+    }
+
+    fn const_declaration(&mut self) -> Result<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect constant name")?;
+        self.consume(TokenType::Equal, "Expect '=' after constant name")?;
+        let initializer = self.expression()?;
+
+        self.end_statement("Expect ';' after constant declaration.")?;
+        Ok(Stmt::Var {
+            name,
+            initializer: Some(initializer),
+            constant: true,
+        })
     }
 
     fn statement(&mut self) -> Result<Stmt> {
@@ -179,6 +250,14 @@ impl Parser {
             self.print_statement()
         } else if self.match_token(TokenType::Return) {
             self.return_statement()
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement()
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement()
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement()
+        } else if self.match_token(TokenType::Import) {
+            self.import_statement()
         } else if self.match_token(TokenType::While) {
             self.while_statement()
         } else if self.match_token(TokenType::For) {
@@ -192,31 +271,141 @@ impl Parser {
 
     fn return_statement(&mut self) -> Result<Stmt> {
         let keyword = self.previous();
-        let value = if !self.check(TokenType::Semicolon) {
-            self.expression()?
+        let value = if !self.check(TokenType::Semicolon) && !self.at_lenient_end() {
+            Some(self.expression()?)
         } else {
-            Expr::Literal(Literal::Nil)
+            None
         };
 
-        self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+        self.end_statement("Expect ';' after return value.")?;
 
         Ok(Stmt::Return { keyword, value })
     }
 
+    fn throw_statement(&mut self) -> Result<Stmt> {
+        let keyword = self.previous();
+        let value = self.expression()?;
+        self.end_statement("Expect ';' after thrown value.")?;
+
+        Ok(Stmt::Throw { keyword, value })
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt> {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.")?;
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+        let name = self.consume(TokenType::Identifier, "Expect error variable name.")?;
+        self.consume(TokenType::RightParen, "Expect ')' after error variable.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch block.")?;
+        let handler = self.block()?;
+
+        Ok(Stmt::Try {
+            body,
+            name,
+            handler,
+        })
+    }
+
+    fn import_statement(&mut self) -> Result<Stmt> {
+        let keyword = self.previous();
+        let path = match &self.peek().typ {
+            TokenType::String(path) => path.clone(),
+            _ => {
+                return Err(ParseError::new(
+                    self.peek().clone(),
+                    "Expect file path after 'import'.",
+                ))
+            }
+        };
+        self.advance();
+        self.end_statement("Expect ';' after import path.")?;
+
+        Ok(Stmt::Import { keyword, path })
+    }
+
+    fn switch_statement(&mut self) -> Result<Stmt> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.")?;
+        let subject = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after switch value.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch body.")?;
+
+        let mut cases = Vec::new();
+        let mut default = None;
+        let mut duplicate_default = None;
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            if self.match_token(TokenType::Case) {
+                let value = self.expression()?;
+                self.consume(TokenType::Colon, "Expect ':' after case value.")?;
+                cases.push((value, self.case_body()?));
+            } else if self.match_token(TokenType::Default) {
+                let keyword = self.previous();
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.")?;
+                let body = self.case_body()?;
+                if default.is_some() {
+                    // Reported after the whole switch is parsed, so we don't get follow-up
+                    // errors from the rest of the body
+                    duplicate_default.get_or_insert(keyword);
+                }
+                default = Some(body);
+            } else {
+                return Err(ParseError::new(
+                    self.peek().clone(),
+                    "Expect 'case' or 'default' in switch body.",
+                ));
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after switch body.")?;
+
+        if let Some(keyword) = duplicate_default {
+            return Err(ParseError::new(
+                keyword,
+                "A switch can only have one default case",
+            ));
+        }
+
+        Ok(Stmt::Switch {
+            subject,
+            cases,
+            default,
+        })
+    }
+
+    // The statements of a case run until the next case, like a block without braces
+    fn case_body(&mut self) -> Result<Vec<Stmt>> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.is_at_end()
+        {
+            statements.push(self.declaration()?);
+        }
+
+        Ok(statements)
+    }
+
     fn for_statement(&mut self) -> Result<Stmt> {
+        let keyword = self.previous();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+
+        if self.check(TokenType::Identifier) && self.check_next(TokenType::In) {
+            return self.for_each_statement();
+        }
+
         let initializer = if self.match_token(TokenType::Semicolon) {
             None
         } else if self.match_token(TokenType::Var) {
-            Some(self.var_declaration()?)
+            Some(Box::new(self.var_declaration()?))
         } else {
-            Some(self.expression_statement()?)
+            Some(Box::new(self.expression_statement()?))
         };
 
         let condition = if !self.check(TokenType::Semicolon) {
-            self.expression()?
+            Some(self.expression()?)
         } else {
-            Expr::Literal(Literal::Bool(true))
+            None
         };
         self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
 
@@ -227,22 +416,30 @@ impl Parser {
         };
         self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
 
-        let mut body = self.statement()?;
-
-        if let Some(increment) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
-        }
+        let body = Box::new(self.statement()?);
 
-        body = Stmt::While {
+        Ok(Stmt::For {
+            keyword,
+            initializer,
             condition,
-            body: Box::new(body),
-        };
+            increment,
+            body,
+        })
+    }
 
-        if let Some(initializer) = initializer {
-            body = Stmt::Block(vec![initializer, body]);
-        }
+    fn for_each_statement(&mut self) -> Result<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect loop variable name.")?;
+        self.consume(TokenType::In, "Expect 'in' after loop variable.")?;
+        let iterable = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        let body = Box::new(self.statement()?);
 
-        Ok(body)
+        Ok(Stmt::ForEach {
+            name,
+            iterable,
+            body,
+        })
     }
 
     fn while_statement(&mut self) -> Result<Stmt> {
@@ -288,14 +485,14 @@ impl Parser {
         // We have already matched and consumed the print-token
         let value = self.expression()?;
 
-        self.consume(TokenType::Semicolon, "Expect ';' after value")?;
+        self.end_statement("Expect ';' after value")?;
 
         Ok(Stmt::Print(value))
     }
 
     fn expression_statement(&mut self) -> Result<Stmt> {
         let expr = self.expression()?;
-        self.consume(TokenType::Semicolon, "Expect ';' after expression")?;
+        self.end_statement("Expect ';' after expression")?;
         Ok(Stmt::Expression(expr))
     }
 
@@ -324,9 +521,21 @@ impl Parser {
                     name,
                     value: Box::new(value),
                 });
+            } else if let Expr::Index {
+                object,
+                bracket,
+                index,
+            } = expr
+            {
+                return Ok(Expr::IndexSet {
+                    object,
+                    bracket,
+                    index,
+                    value: Box::new(value),
+                });
             }
 
-            println!("{}", ParseError::new(equals, "Invalid assignment target"));
+            self.report(ParseError::new(equals, "Invalid assignment target"));
         }
 
         Ok(expr)
@@ -334,7 +543,7 @@ impl Parser {
 
     fn logic_or(&mut self) -> Result<Expr> {
         let mut expr = self.logic_and()?;
-        while self.match_token(TokenType::Or) {
+        while self.match_tokens(&[TokenType::Or, TokenType::PipePipe]) {
             let operator = self.previous();
             let right = self.logic_and()?;
             expr = Expr::Logical {
@@ -349,7 +558,7 @@ impl Parser {
 
     fn logic_and(&mut self) -> Result<Expr> {
         let mut expr = self.equality()?;
-        while self.match_token(TokenType::And) {
+        while self.match_tokens(&[TokenType::And, TokenType::AmpAmp]) {
             let operator = self.previous();
             let right = self.equality()?;
             expr = Expr::Logical {
@@ -454,6 +663,8 @@ impl Parser {
                     object: Box::new(expr),
                     name,
                 };
+            } else if self.match_token(TokenType::LeftBracket) {
+                expr = self.subscript(expr)?;
             } else {
                 break;
             }
@@ -462,11 +673,59 @@ impl Parser {
         Ok(expr)
     }
 
+    // `object[index]` or `object[start:end]`, the `[` is already consumed
+    fn subscript(&mut self, object: Expr) -> Result<Expr> {
+        let bracket = self.previous();
+        let start = if self.match_token(TokenType::Colon) {
+            None
+        } else {
+            let index = self.expression()?;
+            if !self.match_token(TokenType::Colon) {
+                self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                return Ok(Expr::Index {
+                    object: Box::new(object),
+                    bracket,
+                    index: Box::new(index),
+                });
+            }
+            Some(Box::new(index))
+        };
+
+        let end = if self.check(TokenType::RightBracket) {
+            None
+        } else {
+            Some(Box::new(self.expression()?))
+        };
+        self.consume(TokenType::RightBracket, "Expect ']' after slice.")?;
+
+        Ok(Expr::Slice {
+            object: Box::new(object),
+            bracket,
+            start,
+            end,
+        })
+    }
+
     fn finish_call(&mut self, callee: Expr) -> Result<Expr> {
         let mut arguments = Vec::new();
+        let mut named: Vec<(Token, Expr)> = Vec::new();
         if !self.check(TokenType::RightParen) {
             loop {
-                arguments.push(self.expression()?);
+                if self.check(TokenType::Identifier) && self.check_next(TokenType::Colon) {
+                    let name = self.advance();
+                    self.advance();
+                    if named.iter().any(|(other, _)| other.lexeme == name.lexeme) {
+                        return Err(ParseError::new(name, "Duplicate named argument"));
+                    }
+                    named.push((name, self.expression()?));
+                } else if !named.is_empty() {
+                    return Err(ParseError::new(
+                        self.peek().clone(),
+                        "Positional arguments must come before named arguments",
+                    ));
+                } else {
+                    arguments.push(self.expression()?);
+                }
                 if !self.match_token(TokenType::Comma) {
                     break;
                 }
@@ -479,22 +738,84 @@ impl Parser {
             callee: Box::new(callee),
             paren,
             arguments,
+            named,
         })
     }
 
+    // `"a${b}c"` is parsed as `"a" + b + "c"`, with `b` turned into a string first
+    fn interpolation(&mut self, first: &str, line: u32) -> Result<Expr> {
+        let literal = |string: &str, line| Expr::Literal {
+            value: Literal::String(string.into()),
+            line,
+        };
+        let plus = |line| Token::new(TokenType::Plus, "+".to_owned(), line);
+
+        let mut expr = literal(first, line);
+        loop {
+            let value = self.expression()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator: plus(value.line()),
+                right: Box::new(Expr::Stringify(Box::new(value))),
+            };
+
+            let next = self.peek().clone();
+            let (string, done) = match &next.typ {
+                TokenType::Interpolation(string) => (string, false),
+                TokenType::String(string) => (string, true),
+                _ => {
+                    return Err(ParseError::new(
+                        next,
+                        "Expect '}' after interpolated expression",
+                    ))
+                }
+            };
+            self.advance();
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator: plus(next.line),
+                right: Box::new(literal(string, next.line)),
+            };
+
+            if done {
+                return Ok(expr);
+            }
+        }
+    }
+
     fn primary(&mut self) -> Result<Expr> {
         use TokenType::*;
         let next_token = self.advance();
+        let line = next_token.line;
+        let literal = |value| Expr::Literal { value, line };
         Ok(match &next_token.typ {
-            False => Expr::Literal(Literal::Bool(false)),
-            True => Expr::Literal(Literal::Bool(true)),
-            Nil => Expr::Literal(Literal::Nil),
-            Number(number) => Expr::Literal(Literal::Number(*number)),
-            String(string) => Expr::Literal(Literal::String(string.clone())),
+            False => literal(Literal::Bool(false)),
+            True => literal(Literal::Bool(true)),
+            Nil => literal(Literal::Nil),
+            Number(number) => literal(Literal::Number(*number)),
+            String(string) => literal(Literal::String(string.as_str().into())),
+            Interpolation(string) => self.interpolation(string, line)?,
             LeftParen => {
                 let expr = self.expression()?;
                 self.consume(RightParen, "Expect ')' after expression")?;
-                Expr::Grouping(Box::new(expr))
+                Expr::Grouping {
+                    expression: Box::new(expr),
+                    line,
+                }
+            }
+            LeftBracket => {
+                let bracket = self.previous();
+                let mut elements = Vec::new();
+                if !self.check(RightBracket) {
+                    loop {
+                        elements.push(self.expression()?);
+                        if !self.match_token(Comma) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(RightBracket, "Expect ']' after list elements.")?;
+                Expr::List { bracket, elements }
             }
             Identifier => Expr::Variable(VariableExpr {
                 expr_id: next_expr_id(),
@@ -531,6 +852,19 @@ impl Parser {
         }
     }
 
+    // Consumes the `;` ending a statement. In lenient mode it can be left out before a `}` or the
+    // end of the input
+    fn end_statement(&mut self, message: &str) -> Result<()> {
+        if !self.at_lenient_end() {
+            self.consume(TokenType::Semicolon, message)?;
+        }
+        Ok(())
+    }
+
+    fn at_lenient_end(&self) -> bool {
+        self.lenient && (self.is_at_end() || self.check(TokenType::RightBrace))
+    }
+
     fn synchronize(&mut self) {
         use TokenType::*;
         self.advance();
@@ -540,9 +874,13 @@ impl Parser {
                 return;
             }
 
-            if let Class | Fun | Var | For | If | While | Print | Return = self.peek().typ {
+            if let Class | Const | Fun | Var | For | If | While | Print | Return | Switch | Import
+            | Throw | Try = self.peek().typ
+            {
                 return;
             }
+
+            self.advance();
         }
     }
 
@@ -581,6 +919,13 @@ impl Parser {
         self.previous()
     }
 
+    fn check_next(&self, typ: TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => token.typ == typ,
+            None => false,
+        }
+    }
+
     fn is_at_end(&self) -> bool {
         self.peek().typ == TokenType::EOF
     }
@@ -0,0 +1,152 @@
+// The steps from source text to statements that are ready to run, shared by the file runner, the
+// REPL, imports and `Interpreter::eval`
+use crate::{
+    ast::Stmt,
+    error_reporter::{Diagnostic, ErrorReporter},
+    interpreter::Interpreter,
+    parser::Parser,
+    resolver::Resolver,
+    runtime_error::RuntimeError,
+    scanner::Scanner,
+    token::Token,
+};
+
+pub fn scan(source: &str) -> Result<Vec<Token>, RunError> {
+    let mut errors = ErrorReporter::new();
+    let mut scanner = Scanner::new(source, &mut errors);
+    let tokens = scanner.scan_tokens();
+
+    if errors.had_error() {
+        return Err(RunError::TokenizeError(errors.diagnostics));
+    }
+
+    Ok(tokens)
+}
+
+// Parses and resolves the tokens, so the statements are ready to be interpreted. Every error
+// found is returned, not just the first one. When `lenient`, the last `;` in a block or the
+// input can be left out
+pub fn parse(
+    tokens: Vec<Token>,
+    interpreter: &mut Interpreter,
+    lenient: bool,
+) -> Result<Vec<Stmt>, RunError> {
+    let mut errors = ErrorReporter::new();
+    let mut parser = Parser::new(tokens, &mut errors);
+    parser.set_lenient(lenient);
+    let statements = parser.parse();
+
+    if errors.had_error() {
+        return Err(RunError::ParseError(errors.diagnostics));
+    }
+
+    Resolver::new(interpreter, &mut errors).resolve(&statements);
+
+    let (errors, warnings) = errors.into_errors_and_warnings();
+    interpreter.add_warnings(warnings);
+    if !errors.is_empty() {
+        return Err(RunError::ParseError(errors));
+    }
+
+    Ok(statements)
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum RunError {
+    TokenizeError(Vec<Diagnostic>),
+    ParseError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::TokenizeError(diagnostics) | RunError::ParseError(diagnostics) => {
+                let lines = diagnostics
+                    .iter()
+                    .map(|diagnostic| diagnostic.to_string())
+                    .collect::<Vec<_>>();
+                write!(f, "{}", lines.join("\n"))
+            }
+            RunError::RuntimeError(inner) => write!(f, "{}", inner),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_reporter::{Location, Severity};
+
+    fn parse_errors(source: &str) -> Vec<Diagnostic> {
+        let tokens = scan(source).expect("Source should scan");
+        match parse(tokens, &mut Interpreter::new(), false) {
+            Err(RunError::ParseError(diagnostics)) => diagnostics,
+            other => panic!("Expected parse errors, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parse_returns_every_error() {
+        let diagnostics = parse_errors("var 1;\nprint (2;\nprint 3");
+
+        let locations = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.location.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            locations,
+            vec![
+                (1, Some(Location::Token("1".to_owned()))),
+                (2, Some(Location::Token(";".to_owned()))),
+                (3, Some(Location::End)),
+            ]
+        );
+    }
+
+    #[test]
+    fn diagnostic_display() {
+        let diagnostics = parse_errors("print 3");
+        assert_eq!(
+            diagnostics[0].to_string(),
+            format!("[line 1] Error at end: {}", diagnostics[0].message)
+        );
+
+        let diagnostic = Diagnostic {
+            line: 2,
+            location: None,
+            message: "Unterminated string".to_owned(),
+            severity: Severity::Error,
+        };
+        assert_eq!(
+            diagnostic.to_string(),
+            "[line 2] Error: Unterminated string"
+        );
+    }
+
+    #[test]
+    fn lenient_parse_allows_missing_last_semicolon() {
+        let statements = parse(scan("var x = 1").unwrap(), &mut Interpreter::new(), true)
+            .expect("Lenient parse should accept a missing semicolon");
+        assert_eq!(statements.len(), 1);
+
+        let strict = parse(scan("var x = 1").unwrap(), &mut Interpreter::new(), false);
+        assert!(matches!(strict, Err(RunError::ParseError(_))));
+    }
+
+    // Recovering from an error inside a statement used to loop forever, because `synchronize`
+    // only stopped at a `;` or a keyword and never moved past the tokens in between
+    #[test]
+    fn parse_recovers_from_error_inside_statement() {
+        let diagnostics = parse_errors("print (1 2 3);\nprint (4 5);\nprint 6;");
+
+        let lines = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![1, 2]);
+    }
+}
@@ -1,17 +1,40 @@
 use crate::{
-    ast::{Expr, Literal, Stmt, StmtFunction, VariableExpr},
+    ast::{Expr, Stmt, StmtFunction, VariableExpr},
     error_reporter::ErrorReporter,
     interpreter::Interpreter,
     token::Token,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub struct Resolver<'a> {
     interpreter: &'a mut Interpreter,
-    scopes: Vec<HashMap<String, bool>>,
+    scopes: Vec<HashMap<String, Local>>,
     errors: &'a mut ErrorReporter,
     current_function: FunctionType,
     current_class: ClassType,
+    // Globals declared at the top level of the code being resolved. Together with the globals
+    // the interpreter already has, these are all the globals that can exist when it runs
+    globals: HashSet<String>,
+    // The globals among them declared with `const`. Constants from earlier lines in the REPL
+    // or from imported files are only known to the interpreter
+    constant_globals: HashSet<String>,
+    // Imported files can declare any global, so undefined globals can't be reported when
+    // there are imports
+    has_imports: bool,
+    // The token declaring the variable each resolved local expression refers to, for editor
+    // tooling
+    definitions: HashMap<usize, Token>,
+    // Every variable reference seen, so a position in the source can be mapped to its `expr_id`
+    references: Vec<(Token, usize)>,
+}
+
+struct Local {
+    // False between declaring the variable and resolving its initializer
+    defined: bool,
+    // Declared with `const`, so it can't be assigned to
+    constant: bool,
+    // For `this` and `super` this is the class that declares them
+    declaration: Token,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -37,10 +60,46 @@ impl<'a> Resolver<'a> {
             errors,
             current_function: FunctionType::None,
             current_class: ClassType::None,
+            globals: HashSet::new(),
+            constant_globals: HashSet::new(),
+            has_imports: false,
+            definitions: HashMap::new(),
+            references: Vec::new(),
         }
     }
 
+    /// The token declaring the local variable `expr_id` refers to. Globals aren't tracked, so
+    /// they have no definition
+    pub fn definition_of(&self, expr_id: usize) -> Option<Token> {
+        self.definitions.get(&expr_id).cloned()
+    }
+
+    /// Like `definition_of`, for the first reference to `name` on `line`
+    pub fn definition_at(&self, line: u32, name: &str) -> Option<Token> {
+        let (_, expr_id) = self
+            .references
+            .iter()
+            .find(|(token, _)| token.line == line && token.lexeme == name)?;
+        self.definition_of(*expr_id)
+    }
+
     pub fn resolve(&mut self, statements: &[Stmt]) {
+        if self.scopes.is_empty() {
+            // Declared up front, so functions can refer to globals declared after them
+            for statement in statements {
+                match statement {
+                    Stmt::Var { name, constant, .. } => self.declare_global(name, *constant),
+                    Stmt::Class { name, .. } => self.declare_global(name, false),
+                    Stmt::Function(fun) => self.declare_global(&fun.name, false),
+                    _ => {}
+                }
+            }
+            // An import in a block or function still declares its globals
+            if statements.iter().any(contains_import) {
+                self.has_imports = true;
+            }
+        }
+
         for statement in statements {
             self.resolve_stmt(statement);
         }
@@ -56,13 +115,23 @@ impl<'a> Resolver<'a> {
             Stmt::Class {
                 name,
                 methods,
+                fields,
                 superclass,
             } => {
                 let enclosing_class = self.current_class;
-                self.current_class = ClassType::Class;
                 self.declare(name);
                 self.define(name);
 
+                // Field defaults are evaluated in the scope the class is declared in, so they
+                // can't see `this` or `super`
+                for field in fields {
+                    if let Some(initializer) = &field.initializer {
+                        self.resolve_expr(initializer);
+                    }
+                }
+
+                self.current_class = ClassType::Class;
+
                 if let Some(superclass) = superclass {
                     self.current_class = ClassType::SubClass;
                     if superclass.name.lexeme == name.lexeme {
@@ -74,19 +143,35 @@ impl<'a> Resolver<'a> {
                     self.resolve_variable(superclass);
 
                     self.begin_scope();
-                    self.scopes
-                        .last_mut()
-                        .unwrap()
-                        .insert("super".to_owned(), true);
+                    self.scopes.last_mut().unwrap().insert(
+                        "super".to_owned(),
+                        Local {
+                            defined: true,
+                            constant: false,
+                            declaration: name.clone(),
+                        },
+                    );
                 }
 
                 self.begin_scope();
-                self.scopes
-                    .last_mut()
-                    .unwrap()
-                    .insert("this".to_owned(), true);
+                self.scopes.last_mut().unwrap().insert(
+                    "this".to_owned(),
+                    Local {
+                        defined: true,
+                        constant: false,
+                        declaration: name.clone(),
+                    },
+                );
 
+                let mut method_names = HashSet::new();
                 for method in methods {
+                    if !method_names.insert(&method.name.lexeme) {
+                        self.errors.error(
+                            method.name.line,
+                            format!("Duplicate method name '{}' in class.", method.name.lexeme),
+                        );
+                    }
+
                     let declaration = if method.name.lexeme == "init" {
                         FunctionType::Initializer
                     } else {
@@ -103,7 +188,43 @@ impl<'a> Resolver<'a> {
 
                 self.current_class = enclosing_class;
             }
-            Stmt::Expression(stmt) => self.resolve_expr(stmt),
+            Stmt::Expression(stmt) => {
+                if is_pure(stmt) {
+                    self.errors
+                        .warning(stmt.line(), "Expression result is unused".to_owned());
+                }
+                self.resolve_expr(stmt)
+            }
+            // The initializer gets a scope of its own, shared by every iteration
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.resolve_stmt(initializer);
+                }
+                for expr in condition.iter().chain(increment) {
+                    self.resolve_expr(expr);
+                }
+                self.resolve_stmt(body);
+                self.end_scope();
+            }
+            Stmt::ForEach {
+                name,
+                iterable,
+                body,
+            } => {
+                self.resolve_expr(iterable);
+                self.begin_scope();
+                self.declare(name);
+                self.define(name);
+                self.resolve_stmt(body);
+                self.end_scope();
+            }
             Stmt::Function(fun) => {
                 self.declare(&fun.name);
                 self.define(&fun.name);
@@ -120,28 +241,75 @@ impl<'a> Resolver<'a> {
                     self.resolve_stmt(else_branch);
                 }
             }
+            // The imported file is resolved on its own when it is imported
+            Stmt::Import { .. } => {}
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                self.resolve_expr(subject);
+                for (value, body) in cases {
+                    self.resolve_expr(value);
+                    self.begin_scope();
+                    self.resolve(body);
+                    self.end_scope();
+                }
+                if let Some(body) = default {
+                    self.begin_scope();
+                    self.resolve(body);
+                    self.end_scope();
+                }
+            }
             Stmt::Return { value, keyword } => {
                 if let FunctionType::None = self.current_function {
                     self.errors
                         .error(keyword.line, "Cannot return from top-level code".to_owned())
                 }
-                if let FunctionType::Initializer = self.current_function {
-                    if value != &Expr::Literal(Literal::Nil) {
+                if let Some(value) = value {
+                    if let FunctionType::Initializer = self.current_function {
                         self.errors.error(
                             keyword.line,
-                            "Cannot return from inside an initiaizer.".to_owned(),
+                            "Cannot return a value from an initializer".to_owned(),
                         );
                     }
+                    self.resolve_expr(value)
                 }
-                self.resolve_expr(value)
             }
             Stmt::Print(stmt) => self.resolve_expr(stmt),
-            Stmt::Var { name, initializer } => {
+            Stmt::Throw { value, .. } => self.resolve_expr(value),
+            Stmt::Try {
+                body,
+                name,
+                handler,
+            } => {
+                self.begin_scope();
+                self.resolve(body);
+                self.end_scope();
+
+                self.begin_scope();
+                self.declare(name);
+                self.define(name);
+                self.resolve(handler);
+                self.end_scope();
+            }
+            Stmt::Var {
+                name,
+                initializer,
+                constant,
+            } => {
                 self.declare(name);
                 if let Some(initializer) = initializer {
                     self.resolve_expr(initializer);
                 }
-                self.define(name)
+                self.define(name);
+                if let Some(local) = self
+                    .scopes
+                    .last_mut()
+                    .and_then(|scope| scope.get_mut(&name.lexeme))
+                {
+                    local.constant = *constant;
+                }
             }
             Stmt::While { condition, body } => {
                 self.resolve_expr(condition);
@@ -150,7 +318,7 @@ impl<'a> Resolver<'a> {
         }
     }
 
-    fn resolve_expr(&mut self, expr: &Expr) {
+    pub fn resolve_expr(&mut self, expr: &Expr) {
         match expr {
             Expr::Assign {
                 expr_id,
@@ -158,6 +326,7 @@ impl<'a> Resolver<'a> {
                 value,
             } => {
                 self.resolve_expr(value);
+                self.check_assignable(name);
                 self.resolve_local(*expr_id, name);
             }
             Expr::Binary { left, right, .. } => {
@@ -165,21 +334,52 @@ impl<'a> Resolver<'a> {
                 self.resolve_expr(right);
             }
             Expr::Call {
-                callee, arguments, ..
+                callee,
+                arguments,
+                named,
+                ..
             } => {
                 self.resolve_expr(callee);
-                for arg in arguments {
+                for arg in arguments.iter().chain(named.iter().map(|(_, value)| value)) {
                     self.resolve_expr(arg);
                 }
             }
             Expr::Get { object, .. } => self.resolve_expr(object),
-            Expr::Grouping(expr) => self.resolve_expr(expr),
-            Expr::Literal(..) => { /* Nothing to do */ }
+            Expr::Grouping { expression, .. } => self.resolve_expr(expression),
+            Expr::Index { object, index, .. } => {
+                self.resolve_expr(object);
+                self.resolve_expr(index);
+            }
+            Expr::Slice {
+                object, start, end, ..
+            } => {
+                self.resolve_expr(object);
+                for bound in start.iter().chain(end) {
+                    self.resolve_expr(bound);
+                }
+            }
+            Expr::IndexSet {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+                self.resolve_expr(index);
+            }
+            Expr::List { elements, .. } => {
+                for element in elements {
+                    self.resolve_expr(element);
+                }
+            }
+            Expr::Literal { .. } => { /* Nothing to do */ }
             Expr::Logical { left, right, .. } => {
                 self.resolve_expr(left);
                 self.resolve_expr(right);
             }
             Expr::Unary { right, .. } => self.resolve_expr(right),
+            Expr::Stringify(expression) => self.resolve_expr(expression),
             Expr::Variable(variable) => self.resolve_variable(variable),
             Expr::Set { object, value, .. } => {
                 self.resolve_expr(value);
@@ -214,7 +414,7 @@ impl<'a> Resolver<'a> {
 
     fn resolve_variable(&mut self, VariableExpr { expr_id, name }: &VariableExpr) {
         if let Some(scope) = self.scopes.last() {
-            if scope.get(&name.lexeme) == Some(&false) {
+            if let Some(Local { defined: false, .. }) = scope.get(&name.lexeme) {
                 self.errors.error(
                     name.line,
                     "Cannot read local variable in its own initializer".to_owned(),
@@ -229,9 +429,18 @@ impl<'a> Resolver<'a> {
         let enclosing_function = self.current_function;
         self.current_function = typ;
         self.begin_scope();
-        for param in &fun.params {
-            self.declare(param);
-            self.define(param);
+        for param in fun.params.iter() {
+            // Defaults are evaluated when the function is called, and can see the
+            // parameters before them
+            if let Some(default) = &param.default {
+                self.resolve_expr(default);
+            }
+            self.declare(&param.name);
+            self.define(&param.name);
+        }
+        if let Some(rest) = &fun.rest {
+            self.declare(rest);
+            self.define(rest);
         }
         self.resolve(&fun.body);
         self.end_scope();
@@ -239,24 +448,87 @@ impl<'a> Resolver<'a> {
     }
 
     fn resolve_local(&mut self, expr_id: usize, name: &Token) {
-        for (index, scope) in self.scopes.iter().enumerate() {
-            if scope.contains_key(&name.lexeme) {
-                self.interpreter
-                    .resolve(expr_id, self.scopes.len() - 1 - index);
+        self.references.push((name.clone(), expr_id));
+
+        // Walk from the innermost scope outwards, so the closest declaration wins
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(local) = scope.get(&name.lexeme) {
+                self.definitions.insert(expr_id, local.declaration.clone());
+                self.interpreter.resolve(expr_id, depth);
                 return;
             }
         }
+
+        // Not found in any scope, so it has to be a global
+        if self.interpreter.check_globals()
+            && !self.has_imports
+            && !self.globals.contains(&name.lexeme)
+            && !self.interpreter.is_global(&name.lexeme)
+        {
+            self.errors
+                .error(name.line, format!("Undefined variable '{}'", name.lexeme));
+        }
+    }
+
+    // Only the innermost declaration of the name matters, so a constant can be shadowed by a
+    // variable in an inner scope
+    fn check_assignable(&mut self, name: &Token) {
+        let constant = match self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.lexeme))
+        {
+            Some(local) => local.constant,
+            None if self.globals.contains(&name.lexeme) => {
+                self.constant_globals.contains(&name.lexeme)
+            }
+            None => self.interpreter.is_constant_global(&name.lexeme),
+        };
+        if constant {
+            self.errors.error(
+                name.line,
+                format!("Cannot assign to constant '{}'", name.lexeme),
+            );
+        }
     }
 
+    // Globals can be redeclared, unless the old or the new declaration is a constant
+    fn declare_global(&mut self, name: &Token, constant: bool) {
+        let is_new = self.globals.insert(name.lexeme.clone());
+        if self.constant_globals.contains(&name.lexeme) || (constant && !is_new) {
+            self.errors.error(
+                name.line,
+                format!("Cannot redeclare constant '{}'", name.lexeme),
+            );
+        }
+        if constant {
+            self.constant_globals.insert(name.lexeme.clone());
+        }
+    }
+
+    // Globals can be redeclared, but locals can't
     fn declare(&mut self, name: &Token) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name.lexeme.clone(), false);
+            let local = Local {
+                defined: false,
+                constant: false,
+                declaration: name.clone(),
+            };
+            if scope.insert(name.lexeme.clone(), local).is_some() {
+                self.errors.error(
+                    name.line,
+                    "Variable with this name already declared in this scope".to_owned(),
+                );
+            }
         }
     }
 
     fn define(&mut self, name: &Token) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name.lexeme.clone(), true);
+            if let Some(local) = scope.get_mut(&name.lexeme) {
+                local.defined = true;
+            }
         }
     }
 
@@ -268,3 +540,50 @@ impl<'a> Resolver<'a> {
         self.scopes.pop();
     }
 }
+
+fn contains_import(stmt: &Stmt) -> bool {
+    let any = |statements: &[Stmt]| statements.iter().any(contains_import);
+    match stmt {
+        Stmt::Import { .. } => true,
+        Stmt::Block(statements) => any(statements),
+        Stmt::Class { methods, .. } => methods.iter().any(|method| any(&method.body)),
+        Stmt::Function(fun) => any(&fun.body),
+        Stmt::For { body, .. } | Stmt::ForEach { body, .. } | Stmt::While { body, .. } => {
+            contains_import(body)
+        }
+        Stmt::If {
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            contains_import(then_branch)
+                || else_branch
+                    .as_ref()
+                    .is_some_and(|else_branch| contains_import(else_branch))
+        }
+        Stmt::Switch { cases, default, .. } => {
+            cases.iter().any(|(_, body)| any(body)) || default.as_deref().is_some_and(any)
+        }
+        Stmt::Try { body, handler, .. } => any(body) || any(handler),
+        Stmt::Expression(_)
+        | Stmt::Print(_)
+        | Stmt::Return { .. }
+        | Stmt::Throw { .. }
+        | Stmt::Var { .. } => false,
+    }
+}
+
+// Whether evaluating the expression can't have any side effects, so using it as a statement is
+// most likely a mistake
+fn is_pure(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal { .. } | Expr::Variable(_) | Expr::This { .. } => true,
+        Expr::Grouping { expression, .. } => is_pure(expression),
+        Expr::Unary { right, .. } => is_pure(right),
+        Expr::Stringify(expression) => is_pure(expression),
+        Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+            is_pure(left) && is_pure(right)
+        }
+        _ => false,
+    }
+}
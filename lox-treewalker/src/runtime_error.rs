@@ -1,19 +1,53 @@
+use std::rc::Rc;
+
 use crate::{
     error_reporter::format_err,
     token::{Token, TokenType},
-    value::Value,
+    value::{Function, Value},
 };
 
 #[derive(Debug)]
 pub enum RuntimeError {
-    Error { token: Token, message: String },
+    Error {
+        line: u32,
+        location: String,
+        message: String,
+    },
+    // From a `throw`, with the value for a `catch` to get
+    Thrown {
+        line: u32,
+        value: Value,
+    },
     Return(Value),
+    // A `return` whose value is a call to a Lox function. The function being returned from
+    // makes the call instead, so tail recursion doesn't grow the Rust stack
+    TailCall {
+        function: Rc<Function>,
+        arguments: Vec<Value>,
+        named: Vec<(Token, Value)>,
+    },
 }
 
 impl RuntimeError {
     pub fn new(token: Token, message: impl Into<String>) -> RuntimeError {
+        let location = if token.typ == TokenType::EOF {
+            " at end".to_owned()
+        } else {
+            format!(" at '{}'", token.lexeme)
+        };
+
+        RuntimeError::Error {
+            line: token.line,
+            location,
+            message: message.into(),
+        }
+    }
+
+    // For errors that don't belong to a single token
+    pub fn at_line(line: u32, message: impl Into<String>) -> RuntimeError {
         RuntimeError::Error {
-            token,
+            line,
+            location: String::new(),
             message: message.into(),
         }
     }
@@ -24,18 +58,16 @@ pub type Result<T> = std::result::Result<T, RuntimeError>;
 impl std::fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RuntimeError::Error { token, message } => {
-                if token.typ == TokenType::EOF {
-                    write!(f, "{}", format_err(token.line, " at end", &message))
-                } else {
-                    write!(
-                        f,
-                        "{}",
-                        format_err(token.line, &format!(" at '{}'", token.lexeme), &message)
-                    )
-                }
+            RuntimeError::Error {
+                line,
+                location,
+                message,
+            } => write!(f, "{}", format_err(*line, location, message)),
+            RuntimeError::Thrown { line, value } => {
+                write!(f, "{}", format_err(*line, "", &value.to_string()))
             }
             RuntimeError::Return(..) => write!(f, "Return"),
+            RuntimeError::TailCall { .. } => write!(f, "Tail call"),
         }
     }
 }
@@ -7,40 +7,64 @@ use crate::{
 
 static KEYWORDS: phf::Map<&'static str, TokenType> = phf_map! {
     "and" => TokenType::And,
+    "case" => TokenType::Case,
+    "catch" => TokenType::Catch,
     "class" => TokenType::Class,
+    "const" => TokenType::Const,
+    "default" => TokenType::Default,
     "else"=> TokenType::Else,
     "false"=> TokenType::False,
     "for"=> TokenType::For,
     "fun"=> TokenType::Fun,
     "if"=> TokenType::If,
+    "import"=> TokenType::Import,
+    "in"=> TokenType::In,
     "nil"=> TokenType::Nil,
     "or"=> TokenType::Or,
     "print"=> TokenType::Print,
     "return"=> TokenType::Return,
     "super"=> TokenType::Super,
+    "switch"=> TokenType::Switch,
     "this"=> TokenType::This,
+    "throw"=> TokenType::Throw,
     "true"=> TokenType::True,
+    "try"=> TokenType::Try,
     "var"=> TokenType::Var,
     "while"=> TokenType::While
 };
 
 pub struct Scanner<'a> {
+    // Four bytes per character, so mostly ASCII source takes about four times the memory of the
+    // text. In return every lookahead is an O(1) index, and the scanner never has to care about
+    // UTF-8 boundaries. Lox scripts are small, so that's the better tradeoff here
     source: Vec<char>,
     tokens: Vec<Token>,
     start: usize,
     current: usize,
     line: u32,
+    // One entry for each `${` we are inside of, counting the `{` opened since then. When a `}`
+    // is found with the count at zero, it ends the interpolation and the string continues
+    interpolations: Vec<usize>,
+    // Comments aren't tokens, so this is all that is known about them afterwards
+    had_comments: bool,
     errors: &'a mut ErrorReporter,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(source: &'a str, errors: &'a mut ErrorReporter) -> Scanner<'a> {
+    pub fn new(source: &str, errors: &'a mut ErrorReporter) -> Scanner<'a> {
+        Self::from_chars(source.chars().collect(), errors)
+    }
+
+    /// For callers that already have the source as chars, so it isn't collected a second time
+    pub fn from_chars(source: Vec<char>, errors: &'a mut ErrorReporter) -> Scanner<'a> {
         Scanner {
-            source: source.chars().collect(),
+            source,
             tokens: vec![],
             start: 0,
             current: 0,
             line: 1,
+            interpolations: Vec::new(),
+            had_comments: false,
             errors,
         }
     }
@@ -56,6 +80,10 @@ impl<'a> Scanner<'a> {
         self.tokens.clone()
     }
 
+    pub fn had_comments(&self) -> bool {
+        self.had_comments
+    }
+
     fn scan_token(&mut self) {
         use TokenType::*;
         let c = self.advance();
@@ -63,9 +91,31 @@ impl<'a> Scanner<'a> {
         match c {
             '(' => self.add_token(LeftParen),
             ')' => self.add_token(RightParen),
-            '{' => self.add_token(LeftBrace),
-            '}' => self.add_token(RightBrace),
+            '{' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                self.add_token(LeftBrace)
+            }
+            '}' if self.interpolations.last() == Some(&0) => {
+                self.interpolations.pop();
+                self.string()
+            }
+            '}' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces -= 1;
+                }
+                self.add_token(RightBrace)
+            }
+            '[' => self.add_token(LeftBracket),
+            ']' => self.add_token(RightBracket),
+            ':' => self.add_token(Colon),
             ',' => self.add_token(Comma),
+            '.' if self.peek() == '.' && self.peek_next() == '.' => {
+                self.advance();
+                self.advance();
+                self.add_token(DotDotDot)
+            }
             '.' => self.add_token(Dot),
             '-' => self.add_token(Minus),
             '+' => self.add_token(Plus),
@@ -79,10 +129,14 @@ impl<'a> Scanner<'a> {
             '<' => self.add_token(Less),
             '>' if self.match_next('=') => self.add_token(GreaterEqual),
             '>' => self.add_token(Greater),
+            '&' if self.match_next('&') => self.add_token(AmpAmp),
+            '|' if self.match_next('|') => self.add_token(PipePipe),
             '/' if self.match_next('/') => {
+                self.had_comments = true;
                 while self.peek() != '\n' && !self.is_at_end() {
                     self.advance();
                 }
+                self.line_directive();
             }
             '/' => self.add_token(Slash),
             ' ' | '\r' | '\t' => {
@@ -93,7 +147,7 @@ impl<'a> Scanner<'a> {
             }
             '"' => self.string(),
             '0'..='9' => self.number(),
-            'a'..='z' | 'A'..='Z' | '_' => self.identifier(),
+            c if is_alpha(c) => self.identifier(),
 
             unknown => self
                 .errors
@@ -101,8 +155,29 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    // `//# line 42` makes the next line line 42, so errors in generated code can point at the
+    // source it was generated from. Anything after the number, like a file name, is ignored
+    fn line_directive(&mut self) {
+        // Skip the "//"
+        let comment = self.source[self.start + 2..self.current]
+            .iter()
+            .collect::<String>();
+        let number = match comment.strip_prefix("# line ") {
+            Some(rest) => rest.split_whitespace().next().unwrap_or(""),
+            None => return,
+        };
+
+        match number.parse::<u32>() {
+            // The newline ending the comment will add one
+            Ok(line) if line > 0 => self.line = line - 1,
+            _ => self
+                .errors
+                .error(self.line, format!("Invalid line directive '{}'", number)),
+        }
+    }
+
     fn identifier(&mut self) {
-        while self.peek().is_ascii_alphanumeric() {
+        while is_alpha(self.peek()) || self.peek().is_ascii_digit() {
             self.advance();
         }
 
@@ -119,6 +194,14 @@ impl<'a> Scanner<'a> {
     }
 
     fn number(&mut self) {
+        if self.source[self.start] == '0' {
+            match self.peek() {
+                'x' | 'X' => return self.radix_number(16, "hexadecimal"),
+                'b' | 'B' => return self.radix_number(2, "binary"),
+                _ => {}
+            }
+        }
+
         while self.peek().is_digit(10) {
             self.advance();
         }
@@ -142,12 +225,54 @@ impl<'a> Scanner<'a> {
         ))
     }
 
+    // Scans the rest of a `0x1F` or `0b1010` literal, the leading 0 is already consumed
+    fn radix_number(&mut self, radix: u32, name: &str) {
+        // The "x" or "b"
+        self.advance();
+
+        // Take every alphanumeric character, so `0xG` is an error instead of `0` followed by
+        // the identifier `xG`
+        let mut digits = String::new();
+        while self.peek().is_alphanumeric() {
+            digits.push(self.advance());
+        }
+
+        match u64::from_str_radix(&digits, radix) {
+            Ok(value) => self.add_token(TokenType::Number(value as f64)),
+            Err(_) => {
+                let text = self.source[self.start..self.current]
+                    .iter()
+                    .collect::<String>();
+                self.errors
+                    .error(self.line, format!("Invalid {} literal '{}'", name, text));
+            }
+        }
+    }
+
+    // Scans the rest of a string, after the opening quote or the `}` ending an interpolated
+    // expression
     fn string(&mut self) {
+        let mut value = String::new();
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
+            let c = self.advance();
+            if c == '\n' {
                 self.line += 1;
             }
-            self.advance();
+
+            if c == '\\' && self.peek() == 'u' && self.peek_next() == '{' {
+                if let Some(escaped) = self.unicode_escape() {
+                    value.push(escaped);
+                }
+            } else if c == '\\' && self.peek() == '$' {
+                value.push(self.advance());
+            } else if c == '$' && self.peek() == '{' {
+                self.advance();
+                self.interpolations.push(0);
+                self.add_token(TokenType::Interpolation(value));
+                return;
+            } else {
+                value.push(c);
+            }
         }
 
         // Unterminated string.
@@ -160,12 +285,42 @@ impl<'a> Scanner<'a> {
         // The closing "
         self.advance();
 
-        let value = self.source[self.start + 1..self.current - 1]
-            .iter()
-            .collect();
         self.add_token(TokenType::String(value));
     }
 
+    // Scans the `u{1F600}` part of a unicode escape, the backslash is already consumed
+    fn unicode_escape(&mut self) -> Option<char> {
+        // The "u{"
+        self.advance();
+        self.advance();
+
+        let mut digits = String::new();
+        while self.peek().is_ascii_hexdigit() {
+            digits.push(self.advance());
+        }
+
+        if self.peek() != '}' || digits.is_empty() || digits.len() > 6 {
+            self.errors
+                .error(self.line, "Malformed unicode escape".to_owned());
+            return None;
+        }
+
+        // The closing }
+        self.advance();
+
+        let code_point = u32::from_str_radix(&digits, 16).expect("Error parsing hex digits");
+        match std::char::from_u32(code_point) {
+            Some(c) => Some(c),
+            None => {
+                self.errors.error(
+                    self.line,
+                    format!("Invalid unicode code point {:X}", code_point),
+                );
+                None
+            }
+        }
+    }
+
     fn advance(&mut self) -> char {
         self.current += 1;
         self.source[self.current - 1]
@@ -207,3 +362,29 @@ impl<'a> Scanner<'a> {
         self.current >= self.source.len()
     }
 }
+
+// Identifiers can use letters from any language, keywords are all ASCII so they still only
+// match exactly
+fn is_alpha(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_chars_gives_the_same_tokens_as_new() {
+        let source = "var greeting = \"héllo 👋 ${name}\"; // blåbær\nprint greeting + \"ø\";";
+
+        let mut errors = ErrorReporter::new();
+        let tokens = Scanner::new(source, &mut errors).scan_tokens();
+        assert!(!errors.had_error());
+
+        let mut errors = ErrorReporter::new();
+        let from_chars = Scanner::from_chars(source.chars().collect(), &mut errors).scan_tokens();
+        assert!(!errors.had_error());
+
+        assert_eq!(tokens, from_chars);
+    }
+}
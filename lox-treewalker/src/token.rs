@@ -14,6 +14,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -31,27 +34,41 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    AmpAmp,
+    PipePipe,
+    DotDotDot,
 
     // Literals.
     Identifier,
+    // The part of a string up to a `${`, the expression and the rest of the string follows
+    Interpolation(String),
     String(String),
     Number(f64),
 
     // Keywords.
     And,
+    Case,
+    Catch,
     Class,
+    Const,
+    Default,
     Else,
     False,
     Fun,
     For,
     If,
+    Import,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
+    Switch,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -1,7 +1,7 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, ops::RangeInclusive, rc::Rc, time::Instant};
 
 use crate::{
-    ast::Stmt,
+    ast::{Field, Param, Stmt},
     environment::{get_at, Environment},
     interpreter::Interpreter,
     runtime_error::RuntimeError,
@@ -10,27 +10,94 @@ use crate::{
 
 #[derive(Clone)]
 pub enum Value {
-    String(String),
+    String(Rc<str>),
     Bool(bool),
     Number(f64),
     Nil,
     BuiltinCallable {
-        arity: usize,
-        fun: fn(intepreter: &mut Interpreter, arguments: Vec<Value>) -> Value,
+        arity: RangeInclusive<usize>,
+        // An `Err` is reported as a runtime error at the call site
+        fun: fn(intepreter: &mut Interpreter, arguments: Vec<Value>) -> Result<Value, String>,
     },
     Function(Rc<Function>),
     Class(Rc<Class>),
     Instance(Rc<Instance>),
+    List(Rc<RefCell<Vec<Value>>>),
 }
 
 impl Value {
-    pub fn arity(&self, token: &Token) -> Result<usize, RuntimeError> {
+    // The number as a list index or a count. There is only one number type, so this is where
+    // whole numbers are told apart from the rest
+    pub fn as_index(&self) -> Option<usize> {
+        match self {
+            Value::Number(number) if *number >= 0. && number.fract() == 0. => {
+                Some(*number as usize)
+            }
+            _ => None,
+        }
+    }
+
+    // A copy of a list or an instance, other values are immutable and returned as they are. A
+    // deep copy also copies the lists and instances inside it, keeping any cycles between them
+    pub fn copy(&self, deep: bool) -> Value {
+        self.copy_inner(deep, &mut HashMap::new())
+    }
+
+    // `copies` maps each list or instance that has been copied to its copy
+    fn copy_inner(&self, deep: bool, copies: &mut HashMap<*const (), Value>) -> Value {
+        match self {
+            Value::List(list) => {
+                let key = Rc::as_ptr(list) as *const ();
+                if let Some(copy) = copies.get(&key) {
+                    return copy.clone();
+                }
+
+                let copy = Rc::new(RefCell::new(Vec::new()));
+                copies.insert(key, Value::List(copy.clone()));
+                let elements = list
+                    .borrow()
+                    .iter()
+                    .map(|element| element.copy_element(deep, copies))
+                    .collect();
+                *copy.borrow_mut() = elements;
+                Value::List(copy)
+            }
+            Value::Instance(instance) => {
+                let key = Rc::as_ptr(instance) as *const ();
+                if let Some(copy) = copies.get(&key) {
+                    return copy.clone();
+                }
+
+                let copy = Rc::new(Instance::new(instance.class.clone()));
+                copies.insert(key, Value::Instance(copy.clone()));
+                let fields = instance
+                    .fields
+                    .borrow()
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.copy_element(deep, copies)))
+                    .collect();
+                *copy.fields.borrow_mut() = fields;
+                Value::Instance(copy)
+            }
+            _ => self.clone(),
+        }
+    }
+
+    fn copy_element(&self, deep: bool, copies: &mut HashMap<*const (), Value>) -> Value {
+        if deep {
+            self.copy_inner(deep, copies)
+        } else {
+            self.clone()
+        }
+    }
+
+    pub fn arity(&self, token: &Token) -> Result<RangeInclusive<usize>, RuntimeError> {
         Ok(match &self {
             Value::Function(function) => function.arity(),
-            Value::BuiltinCallable { arity, .. } => *arity,
+            Value::BuiltinCallable { arity, .. } => arity.clone(),
             Value::Class(class) => match class.find_method("init") {
                 Some(method) => method.arity(),
-                None => 0,
+                None => 0..=0,
             },
             _ => Err(RuntimeError::new(
                 token.clone(),
@@ -39,46 +106,87 @@ impl Value {
         })
     }
 
+    pub fn check_arity(
+        arity: &RangeInclusive<usize>,
+        token: &Token,
+        count: usize,
+    ) -> Result<(), RuntimeError> {
+        if arity.contains(&count) {
+            return Ok(());
+        }
+
+        let expected = if arity.start() == arity.end() {
+            format!("{}", arity.start())
+        } else if *arity.end() == usize::MAX {
+            format!("at least {}", arity.start())
+        } else {
+            format!("{} to {}", arity.start(), arity.end())
+        };
+        Err(RuntimeError::new(
+            token.clone(),
+            format!("Expected {} arguments, but got {}.", expected, count),
+        ))
+    }
+
+    fn check_arguments(
+        &self,
+        token: &Token,
+        count: usize,
+        named: &[(Token, Value)],
+    ) -> Result<(), RuntimeError> {
+        match self {
+            Value::Function(function) => function.check_arguments(token, count, named),
+            Value::Class(class) => match class.find_method("init") {
+                Some(initializer) => initializer.check_arguments(token, count, named),
+                None => Function::check_unnamed(named, 0..=0, token, count),
+            },
+            _ => Function::check_unnamed(named, self.arity(token)?, token, count),
+        }
+    }
+
     pub fn call(
         &self,
         interpreter: &mut Interpreter,
         token: &Token,
         arguments: Vec<Value>,
+        named: Vec<(Token, Value)>,
     ) -> Result<Value, RuntimeError> {
-        let arity = self.arity(token)?;
+        self.check_arguments(token, arguments.len(), &named)?;
 
-        if arguments.len() != arity {
-            Err(RuntimeError::new(
-                token.clone(),
-                format!("Expected {} arguments, but got {}.", arity, arguments.len()),
-            ))?
-        }
-
-        Ok(match self {
-            Value::Function(function) => function.call(interpreter, arguments)?,
+        interpreter.enter_call(token)?;
+        let result = match self {
+            Value::Function(function) => function.call(interpreter, arguments, named),
             Value::Class(class) => {
                 let instance = Rc::new(Instance::new(class.clone()));
-                if let Some(initializer) = class.find_method("init") {
-                    initializer
-                        .bind(instance.clone())
-                        .call(interpreter, arguments)?;
-                }
-                Value::Instance(instance)
+                class
+                    .initialize_fields(interpreter, &instance)
+                    .and_then(|_| match class.find_method("init") {
+                        Some(initializer) => initializer
+                            .bind(instance.clone())
+                            .call(interpreter, arguments, named)
+                            .map(|_| Value::Instance(instance)),
+                        None => Ok(Value::Instance(instance)),
+                    })
             }
-            Value::BuiltinCallable { fun, .. } => fun(interpreter, arguments),
+            Value::BuiltinCallable { fun, .. } => fun(interpreter, arguments)
+                .map_err(|message| RuntimeError::new(token.clone(), message)),
             _ => Err(RuntimeError::new(
                 token.clone(),
                 "Can only call functions and classes.".to_owned(),
-            ))?,
-        })
+            )),
+        };
+        interpreter.exit_call();
+
+        result
     }
 }
 
 pub struct Function {
     pub closure: Rc<RefCell<Environment>>,
-    pub name: String,
-    pub params: Vec<Token>,
-    pub body: Vec<Stmt>,
+    pub name: Rc<str>,
+    pub params: Rc<[Param]>,
+    pub rest: Option<Token>,
+    pub body: Rc<[Stmt]>,
     pub is_initializer: bool,
 }
 
@@ -90,27 +198,158 @@ impl Function {
             closure: Rc::new(RefCell::new(environment)),
             name: self.name.clone(),
             params: self.params.clone(),
+            rest: self.rest.clone(),
             body: self.body.clone(),
             is_initializer: self.is_initializer,
         }
     }
 
-    pub fn arity(&self) -> usize {
-        self.params.len()
+    // Parameters with a default value can be left out, and a rest parameter
+    // accepts any number of extra arguments
+    pub fn arity(&self) -> RangeInclusive<usize> {
+        let required = self
+            .params
+            .iter()
+            .filter(|param| param.default.is_none())
+            .count();
+        if self.rest.is_some() {
+            required..=usize::MAX
+        } else {
+            required..=self.params.len()
+        }
+    }
+
+    // Named arguments go to the parameters after the positional arguments. Every parameter
+    // without a default value has to get an argument one way or the other
+    pub fn check_arguments(
+        &self,
+        token: &Token,
+        count: usize,
+        named: &[(Token, Value)],
+    ) -> Result<(), RuntimeError> {
+        if named.is_empty() || (count > self.params.len() && self.rest.is_none()) {
+            return Value::check_arity(&self.arity(), token, count);
+        }
+
+        for (name, _) in named {
+            match self.param_index(&name.lexeme) {
+                None => Err(RuntimeError::new(
+                    name.clone(),
+                    format!("{} has no parameter named '{}'", self.name, name.lexeme),
+                ))?,
+                Some(index) if index < count => Err(RuntimeError::new(
+                    name.clone(),
+                    format!("Parameter '{}' was already given by position", name.lexeme),
+                ))?,
+                Some(_) => {}
+            }
+        }
+
+        let missing = self.params.iter().skip(count).find(|param| {
+            param.default.is_none()
+                && !named
+                    .iter()
+                    .any(|(name, _)| name.lexeme == param.name.lexeme)
+        });
+        match missing {
+            Some(param) => Err(RuntimeError::new(
+                token.clone(),
+                format!("Missing argument for parameter '{}'", param.name.lexeme),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // For callables that don't have parameter names
+    fn check_unnamed(
+        named: &[(Token, Value)],
+        arity: RangeInclusive<usize>,
+        token: &Token,
+        count: usize,
+    ) -> Result<(), RuntimeError> {
+        match named.first() {
+            Some((name, _)) => Err(RuntimeError::new(
+                name.clone(),
+                "Only functions declared in Lox take named arguments",
+            )),
+            None => Value::check_arity(&arity, token, count),
+        }
+    }
+
+    fn param_index(&self, name: &str) -> Option<usize> {
+        self.params
+            .iter()
+            .position(|param| param.name.lexeme == name)
     }
 
     pub fn call(
         &self,
         interpreter: &mut Interpreter,
         arguments: Vec<Value>,
+        named: Vec<(Token, Value)>,
     ) -> Result<Value, RuntimeError> {
-        let mut environment = Environment::new_with_enclosing(&self.closure);
-        for (param, argument) in self.params.iter().zip(arguments) {
-            environment.define(&param.lexeme, argument);
+        // Tail calls come back here instead of recursing, and are run in a loop
+        let mut result = self.run(interpreter, arguments, named);
+        while let Err(RuntimeError::TailCall {
+            function,
+            arguments,
+            named,
+        }) = result
+        {
+            result = function.run(interpreter, arguments, named);
         }
+        result
+    }
 
-        let result = match interpreter.execute_block(&self.body, Rc::new(RefCell::new(environment)))
-        {
+    // Runs the body once, recording the call when profiling. Each tail call is recorded as a
+    // call of its own
+    fn run(
+        &self,
+        interpreter: &mut Interpreter,
+        arguments: Vec<Value>,
+        named: Vec<(Token, Value)>,
+    ) -> Result<Value, RuntimeError> {
+        if !interpreter.is_profiling() {
+            return self.execute_body(interpreter, arguments, named);
+        }
+
+        let start = Instant::now();
+        let result = self.execute_body(interpreter, arguments, named);
+        interpreter.record_call(&self.name, start.elapsed());
+        result
+    }
+
+    // The arguments have been checked with `check_arguments`
+    fn execute_body(
+        &self,
+        interpreter: &mut Interpreter,
+        arguments: Vec<Value>,
+        named: Vec<(Token, Value)>,
+    ) -> Result<Value, RuntimeError> {
+        let environment = Rc::new(RefCell::new(Environment::new_with_enclosing(&self.closure)));
+
+        let mut named = named
+            .into_iter()
+            .map(|(name, value)| (name.lexeme, value))
+            .collect::<HashMap<_, _>>();
+        let mut arguments = arguments.into_iter();
+        for param in self.params.iter() {
+            let argument = arguments
+                .next()
+                .or_else(|| named.remove(&param.name.lexeme));
+            let value = match (argument, &param.default) {
+                (Some(argument), _) => argument,
+                (None, Some(default)) => interpreter.evaluate_in(default, environment.clone())?,
+                (None, None) => unreachable!("Arity is checked before calling"),
+            };
+            environment.borrow_mut().define(&param.name.lexeme, value);
+        }
+        if let Some(rest) = &self.rest {
+            let rest_values = Value::List(Rc::new(RefCell::new(arguments.collect())));
+            environment.borrow_mut().define(&rest.lexeme, rest_values);
+        }
+
+        let result = match interpreter.execute_block(&self.body, environment) {
             Ok(()) => Value::Nil,
             Err(RuntimeError::Return(value)) => value,
             Err(err) => Err(err)?,
@@ -124,9 +363,18 @@ impl Function {
     }
 }
 
+impl std::fmt::Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[Function {}]", self.name)
+    }
+}
+
 pub struct Class {
     name: String,
     methods: HashMap<String, Rc<Function>>,
+    fields: Vec<Field>,
+    // The environment the class is declared in, where field defaults are evaluated
+    closure: Rc<RefCell<Environment>>,
     superclass: Option<Rc<Class>>,
 }
 
@@ -134,15 +382,41 @@ impl Class {
     pub fn new(
         name: &str,
         methods: HashMap<String, Rc<Function>>,
+        fields: Vec<Field>,
+        closure: Rc<RefCell<Environment>>,
         superclass: Option<Rc<Class>>,
     ) -> Self {
         Self {
             name: name.to_owned(),
             methods,
+            fields,
+            closure,
             superclass,
         }
     }
 
+    // Sets the declared fields on a new instance, the superclass's first so a subclass can
+    // override their defaults
+    fn initialize_fields(
+        &self,
+        interpreter: &mut Interpreter,
+        instance: &Instance,
+    ) -> Result<(), RuntimeError> {
+        if let Some(superclass) = &self.superclass {
+            superclass.initialize_fields(interpreter, instance)?;
+        }
+
+        for field in &self.fields {
+            let value = match &field.initializer {
+                Some(initializer) => interpreter.evaluate_in(initializer, self.closure.clone())?,
+                None => Value::Nil,
+            };
+            instance.set(&field.name, value);
+        }
+
+        Ok(())
+    }
+
     pub fn find_method(&self, name: &str) -> Option<Rc<Function>> {
         self.methods.get(name).cloned().or(self
             .superclass
@@ -165,20 +439,37 @@ impl Instance {
     }
 
     pub fn get(self: Rc<Self>, name: &Token) -> Result<Value, RuntimeError> {
-        if let Some(value) = self.fields.borrow().get(&name.lexeme) {
+        self.get_named(&name.lexeme, name)
+    }
+
+    // For `instance["name"]`, where the name is only known at runtime. Errors are reported
+    // at `token`
+    pub fn get_named(self: Rc<Self>, name: &str, token: &Token) -> Result<Value, RuntimeError> {
+        if let Some(value) = self.fields.borrow().get(name) {
             Ok(value.clone())
-        } else if let Some(method) = self.class.find_method(&name.lexeme) {
+        } else if let Some(method) = self.class.find_method(name) {
             Ok(Value::Function(Rc::new(method.clone().bind(self.clone()))))
         } else {
             Err(RuntimeError::new(
-                name.clone(),
-                format!("Undefined property '{}'", name.lexeme),
+                token.clone(),
+                format!("Undefined property '{}'", name),
             ))
         }
     }
 
+    // Sorted, so they come out the same way every time
+    pub fn field_names(&self) -> Vec<String> {
+        let mut names = self.fields.borrow().keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
     pub fn set(&self, name: &Token, value: Value) {
-        self.fields.borrow_mut().insert(name.lexeme.clone(), value);
+        self.set_named(&name.lexeme, value);
+    }
+
+    pub fn set_named(&self, name: &str, value: Value) {
+        self.fields.borrow_mut().insert(name.to_owned(), value);
     }
 }
 
@@ -189,12 +480,42 @@ impl std::fmt::Display for Value {
             Value::Bool(val) => write!(f, "{}", val),
             Value::Number(val) => write!(f, "{}", val),
             Value::Nil => write!(f, "nil"),
-            Value::BuiltinCallable { .. } => write!(f, "[Builtin callable]"),
-            Value::Function(function) => write!(f, "[Function {}]", function.name),
-            Value::Class(class) => write!(f, "[Class {}]", class.name),
-            Value::Instance(instance) => write!(f, "[Instance of Class {}]", instance.class.name),
+            Value::BuiltinCallable { .. } => write!(f, "<native fn>"),
+            Value::Function(function) => write!(f, "<fn {}>", function.name),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::List(list) => write_list(f, list, &mut Vec::new()),
+        }
+    }
+}
+
+// `printing` holds the lists we are inside of, so a list containing itself is printed as `[...]`
+// instead of recursing forever
+fn write_list(
+    f: &mut std::fmt::Formatter<'_>,
+    list: &Rc<RefCell<Vec<Value>>>,
+    printing: &mut Vec<*const RefCell<Vec<Value>>>,
+) -> std::fmt::Result {
+    if printing.contains(&Rc::as_ptr(list)) {
+        return write!(f, "[...]");
+    }
+    printing.push(Rc::as_ptr(list));
+
+    write!(f, "[")?;
+    for (i, element) in list.borrow().iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match element {
+            Value::String(string) => write!(f, "{:?}", string)?,
+            Value::List(inner) => write_list(f, inner, printing)?,
+            element => write!(f, "{}", element)?,
         }
     }
+    write!(f, "]")?;
+
+    printing.pop();
+    Ok(())
 }
 
 impl std::fmt::Debug for Value {
@@ -208,6 +529,7 @@ impl std::fmt::Debug for Value {
             Value::Function(function) => write!(f, "[Function {}]", function.name),
             Value::Class(class) => write!(f, "[Class {}]", class.name),
             Value::Instance(instance) => write!(f, "[Instance of Class {}]", instance.class.name),
+            Value::List(..) => write!(f, "{}", self),
         }
     }
 }
@@ -12,11 +12,21 @@ pub enum OpCode {
     True,
     False,
     Pop,
+    Dup,
     GetLocal,
     SetLocal,
+    // Like `GetLocal` and `SetLocal`, with a two byte slot for functions with more than 256
+    // locals
+    GetLocalLong,
+    SetLocalLong,
     GetGlobal,
     DefineGlobal,
     SetGlobal,
+    // Like `GetGlobal`, `DefineGlobal` and `SetGlobal`, with a two byte slot for programs with
+    // more than 256 globals
+    GetGlobalLong,
+    DefineGlobalLong,
+    SetGlobalLong,
     Equal,
     Greater,
     Less,
@@ -24,6 +34,8 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    // Turns the value on top of the stack into a string, for string interpolation
+    Stringify,
     Not,
     Negate,
     Print,
@@ -31,6 +43,10 @@ pub enum OpCode {
     Jump,
     Loop,
     Call,
+    // Registers a handler for `throw`, the operand is the offset to the catch block
+    PushHandler,
+    PopHandler,
+    Throw,
 }
 
 impl std::fmt::Display for OpCode {
@@ -66,6 +82,20 @@ impl Chunk {
     }
 
     pub fn add_constant(&mut self, value: Value) -> u8 {
+        // There is only room for 256 constants, so reuse an equal one if we already have it.
+        // Strings are interned, so comparing pointers is enough for them
+        let existing = self
+            .constants
+            .iter()
+            .position(|constant| match (constant, &value) {
+                // By bits, so 0 and -0 are kept apart
+                (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+                (a, b) => a == b,
+            });
+        if let Some(existing) = existing {
+            return existing as u8;
+        }
+
         self.constants.push(value);
         (self.constants.len() - 1)
             .try_into()
@@ -76,4 +106,8 @@ impl Chunk {
     pub fn constant(&self, id: u8) -> &Value {
         &self.constants[id as usize]
     }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
 }
@@ -5,6 +5,7 @@ use log::trace;
 use crate::{
     chunk::{Chunk, OpCode},
     debug::disassemble_chunk,
+    globals::Globals,
     object::{ObjFunction, ObjHeap, ObjKind},
     scanner::{Scanner, Token, TokenType},
     value::Value,
@@ -16,8 +17,11 @@ struct Parser<'a> {
     previous: Token<'a>,
     scanner: Scanner<'a>,
     heap: &'a mut ObjHeap,
-    had_error: bool,
+    globals: &'a mut Globals,
+    errors: Vec<CompileError>,
     panic_mode: bool,
+    // Set RLOX_WARN_SHADOWING to get a warning when a local shadows one in an outer scope
+    warn_shadowing: bool,
     compiler: Compiler<'a>,
 }
 
@@ -27,12 +31,22 @@ enum FunctionType {
     Script,
 }
 
+// Locals are addressed with at most two bytes
+const LOCALS_MAX: usize = u16::MAX as usize + 1;
+
 struct Compiler<'a> {
+    // The compiler of the function this one is declared in, `None` for the script
+    enclosing: Option<Box<Compiler<'a>>>,
     function: ObjFunction,
     function_type: FunctionType,
 
     locals: Vec<Local<'a>>,
     scope_depth: i32,
+    loops: Vec<Loop>,
+    // How many `try` blocks we are inside of in this function
+    handlers: usize,
+    // The variables of enclosing functions this function uses
+    upvalues: Vec<Upvalue>,
 }
 
 impl<'a> Compiler<'a> {
@@ -45,6 +59,7 @@ impl<'a> Compiler<'a> {
 
         let local = Local {
             depth: 0,
+            constant: false,
             name: Token {
                 line: 0,
                 str: "",
@@ -56,14 +71,18 @@ impl<'a> Compiler<'a> {
         locals.push(local);
 
         Compiler {
+            enclosing: None,
             function,
             function_type,
             locals,
             scope_depth: 0,
+            loops: Vec::new(),
+            handlers: 0,
+            upvalues: Vec::new(),
         }
     }
 
-    fn resolve_local(&self, name: Token) -> (Option<u8>, Option<&'static str>) {
+    fn resolve_local(&self, name: Token) -> (Option<u16>, Option<&'static str>) {
         let mut error = None;
         for (i, local) in self.locals.iter().enumerate().rev() {
             if local.name.str == name.str {
@@ -76,40 +95,137 @@ impl<'a> Compiler<'a> {
 
         (None, error)
     }
+
+    // Looks for `name` among the locals of the enclosing functions, innermost first. Every
+    // function in between gets an upvalue for it, so the value can be passed down the chain
+    fn resolve_upvalue(&mut self, name: Token) -> (Option<usize>, Option<&'static str>) {
+        let enclosing = match &mut self.enclosing {
+            Some(enclosing) => enclosing,
+            None => return (None, None),
+        };
+
+        if let (Some(local), error) = enclosing.resolve_local(name) {
+            return (Some(self.add_upvalue(local, true)), error);
+        }
+        if let (Some(upvalue), error) = enclosing.resolve_upvalue(name) {
+            return (Some(self.add_upvalue(upvalue as u16, false)), error);
+        }
+
+        (None, None)
+    }
+
+    fn add_upvalue(&mut self, index: u16, is_local: bool) -> usize {
+        let upvalue = Upvalue { index, is_local };
+        if let Some(existing) = self.upvalues.iter().position(|other| *other == upvalue) {
+            return existing;
+        }
+
+        self.upvalues.push(upvalue);
+        self.upvalues.len() - 1
+    }
 }
 
 struct Local<'a> {
     name: Token<'a>,
     depth: i32,
+    // Declared with `const`, so it can't be assigned to
+    constant: bool,
+}
+
+#[derive(PartialEq)]
+struct Upvalue {
+    // A local slot in the enclosing function when `is_local`, otherwise an upvalue of it
+    index: u16,
+    is_local: bool,
+}
+
+struct Loop {
+    // The scope depth outside the loop body. A `break` pops every local deeper than this
+    scope_depth: i32,
+    // The try blocks outside the loop. A `break` pops the handlers of any others
+    handlers: usize,
+    // Jumps emitted by `break` that should be patched to the end of the loop
+    break_jumps: Vec<usize>,
 }
 
-pub fn compile(source: &str, heap: &mut ObjHeap) -> Result<ObjFunction, ()> {
-    let scanner = Scanner::new(source);
-    let mut parser = Parser {
-        // Add some tokens so that we can create a parser. This will soon be overwritten
-        current: Token {
-            typ: TokenType::NOOP,
-            str: "",
-            line: 1,
-        },
-        previous: Token {
-            typ: TokenType::NOOP,
-            str: "",
-            line: 1,
-        },
-        scanner,
-        had_error: false,
-        panic_mode: false,
-        heap,
-        compiler: Compiler::new(FunctionType::Script, None),
-    };
+/// An error found while compiling. Compiling goes on after an error, to find as many as
+/// possible in one go.
+#[derive(Clone, Debug)]
+pub struct CompileError {
+    pub line: usize,
+    // `None` if there is no token to point at, like for errors from the scanner
+    pub location: Option<Location>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    // The text of the token the error is at
+    Token(String),
+    End,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] Error", self.line)?;
+        match &self.location {
+            Some(Location::Token(lexeme)) => write!(f, " at '{}'", lexeme)?,
+            Some(Location::End) => write!(f, " at end")?,
+            None => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+pub fn compile(
+    source: &str,
+    heap: &mut ObjHeap,
+    globals: &mut Globals,
+) -> Result<ObjFunction, Vec<CompileError>> {
+    let mut parser = Parser::new(source, heap, globals);
     let function = parser.compile()?;
 
     Ok(function)
 }
 
+/// Compiles `source` as a single expression. The resulting function returns the value of
+/// the expression instead of nil.
+pub fn compile_expression(
+    source: &str,
+    heap: &mut ObjHeap,
+    globals: &mut Globals,
+) -> Result<ObjFunction, Vec<CompileError>> {
+    let mut parser = Parser::new(source, heap, globals);
+    let function = parser.compile_expression()?;
+
+    Ok(function)
+}
+
 impl<'a> Parser<'a> {
-    fn compile(&mut self) -> Result<ObjFunction, ()> {
+    fn new(source: &'a str, heap: &'a mut ObjHeap, globals: &'a mut Globals) -> Parser<'a> {
+        Parser {
+            // Add some tokens so that we can create a parser. This will soon be overwritten
+            current: Token {
+                typ: TokenType::NOOP,
+                str: "",
+                line: 1,
+            },
+            previous: Token {
+                typ: TokenType::NOOP,
+                str: "",
+                line: 1,
+            },
+            scanner: Scanner::new(source),
+            errors: Vec::new(),
+            panic_mode: false,
+            warn_shadowing: std::env::var_os("RLOX_WARN_SHADOWING").is_some(),
+            heap,
+            globals,
+            compiler: Compiler::new(FunctionType::Script, None),
+        }
+    }
+
+    fn compile(&mut self) -> Result<ObjFunction, Vec<CompileError>> {
         self.advance();
 
         while !self.match_token(TokenType::EOF) {
@@ -118,10 +234,26 @@ impl<'a> Parser<'a> {
 
         let function = self.end_compiler();
 
-        if self.had_error {
-            Err(())
+        if self.errors.is_empty() {
+            Ok(function)
         } else {
+            Err(mem::take(&mut self.errors))
+        }
+    }
+
+    fn compile_expression(&mut self) -> Result<ObjFunction, Vec<CompileError>> {
+        self.advance();
+
+        self.expression();
+        self.consume(TokenType::EOF, "Expect end of expression");
+        self.emit_opcode(OpCode::Return);
+
+        let function = self.end_compiler();
+
+        if self.errors.is_empty() {
             Ok(function)
+        } else {
+            Err(mem::take(&mut self.errors))
         }
     }
 
@@ -132,7 +264,7 @@ impl<'a> Parser<'a> {
 
         #[cfg(feature = "print-code")]
         {
-            if !self.had_error {
+            if self.errors.is_empty() {
                 let heap = self.heap.clone();
                 disassemble_chunk(
                     self.current_chunk(),
@@ -201,20 +333,30 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn identifier_constant(&mut self, name: Token) -> u8 {
+    fn global_slot(&mut self, name: Token) -> u16 {
         let string = self.heap.copy_string(name.str);
-        self.make_constant(Value::Obj(string))
+        match self.globals.resolve(string) {
+            Some(slot) => slot,
+            None => {
+                self.error("Too many global variables");
+                0
+            }
+        }
     }
 
-    fn parse_variable(&mut self, error_message: &'static str) -> u8 {
+    fn parse_variable(&mut self, error_message: &'static str) -> u16 {
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
         if self.compiler.scope_depth > 0 {
-            0
-        } else {
-            self.identifier_constant(self.previous)
+            return 0;
+        }
+
+        let global = self.global_slot(self.previous);
+        if self.globals.is_const(global) {
+            self.error("Cannot redeclare a constant");
         }
+        global
     }
 
     fn declare_variable(&mut self) {
@@ -226,19 +368,27 @@ impl<'a> Parser<'a> {
         let name = self.previous;
 
         let mut exists = false;
+        let mut shadowed = None;
         for local in self.compiler.locals.iter().rev() {
-            if local.depth != -1 && local.depth < self.compiler.scope_depth {
-                break;
-            }
-
             if local.name.str == name.str {
-                exists = true;
+                if local.depth != -1 && local.depth < self.compiler.scope_depth {
+                    shadowed = Some(local.name);
+                } else {
+                    exists = true;
+                }
                 break;
             }
         }
 
         if exists {
             self.error("Variable with this name already declared in this scope");
+        } else if let Some(shadowed) = shadowed {
+            if self.warn_shadowing {
+                self.warning(&format!(
+                    "'{}' shadows the local variable declared on line {}",
+                    name.str, shadowed.line
+                ));
+            }
         }
 
         self.add_local(name);
@@ -251,7 +401,7 @@ impl<'a> Parser<'a> {
         self.compiler.locals.last_mut().unwrap().depth = self.compiler.scope_depth;
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: u16) {
         if self.compiler.scope_depth > 0 {
             // No need to define the local variable. It's already on the stack, exactly where
             // we want it to be
@@ -259,23 +409,36 @@ impl<'a> Parser<'a> {
             return;
         }
 
-        self.emit_opcode_byte(OpCode::DefineGlobal, global);
+        if global > u8::MAX as u16 {
+            self.emit_opcode_short(OpCode::DefineGlobalLong, global);
+        } else {
+            self.emit_opcode_byte(OpCode::DefineGlobal, global as u8);
+        }
     }
 
     fn add_local(&mut self, name: Token<'a>) {
-        if self.compiler.locals.len() == 256 {
+        if self.compiler.locals.len() == LOCALS_MAX {
             self.error("Too many local variables in function");
             return;
         }
 
-        self.compiler.locals.push(Local { name, depth: -1 })
+        self.compiler.locals.push(Local {
+            name,
+            depth: -1,
+            constant: false,
+        });
+
+        let function = &mut self.compiler.function;
+        function.max_slots = function.max_slots.max(self.compiler.locals.len());
     }
 
     fn declaration(&mut self) {
         if self.match_token(TokenType::Fun) {
             self.fun_declaration();
         } else if self.match_token(TokenType::Var) {
-            self.var_declaration();
+            self.var_declaration(false);
+        } else if self.match_token(TokenType::Const) {
+            self.var_declaration(true);
         } else {
             self.statement();
         }
@@ -296,6 +459,14 @@ impl<'a> Parser<'a> {
             self.return_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -316,6 +487,8 @@ impl<'a> Parser<'a> {
         self.consume(TokenType::RightBrace, "Expect '{' after block");
     }
 
+    // Function declarations are not hoisted. Globals are looked up when the code runs, so a
+    // function can call one declared after it, as long as it's not called before then
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect function name");
 
@@ -325,11 +498,12 @@ impl<'a> Parser<'a> {
     }
 
     fn function(&mut self, function_type: FunctionType) {
-        let mut compiler = Compiler::new(function_type, Some(self.previous.str.to_owned()));
-        // This is not the way the book is doing it. Let's see if it works out. If not
-        // we need to use the enclosing-thing. See
-        // https://craftinginterpreters.com/calls-and-functions.html#function-declarations
-        mem::swap(&mut self.compiler, &mut compiler);
+        // The new compiler takes the place of the current one, which it keeps as its enclosing
+        // compiler until the function is done
+        let name = Some(self.previous.str.to_owned());
+        let compiler = Compiler::new(function_type, name);
+        let enclosing = mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
         self.begin_scope();
 
         self.consume(TokenType::LeftParen, "Expect '(' after function name");
@@ -356,17 +530,25 @@ impl<'a> Parser<'a> {
         self.block();
 
         let function = self.end_compiler();
-        mem::swap(&mut self.compiler, &mut compiler);
+        let enclosing = self
+            .compiler
+            .enclosing
+            .take()
+            .expect("A function compiler always has an enclosing compiler");
+        self.compiler = *enclosing;
         let function = self.heap.allocate_obj(ObjKind::Function(function));
         let function_constant = self.make_constant(Value::Obj(function));
         self.emit_opcode_byte(OpCode::Constant, function_constant);
     }
 
-    fn var_declaration(&mut self) {
+    // `var name = value;`, or `const name = value;` when `constant`
+    fn var_declaration(&mut self, constant: bool) {
         let global = self.parse_variable("Expect variable name");
 
         if self.match_token(TokenType::Equal) {
             self.expression();
+        } else if constant {
+            self.error_at_current("Expect '=' after constant name");
         } else {
             self.emit_opcode(OpCode::Nil);
         }
@@ -376,6 +558,13 @@ impl<'a> Parser<'a> {
             "Expect ';' after variable declaration",
         );
 
+        if constant {
+            if self.compiler.scope_depth > 0 {
+                self.compiler.locals.last_mut().unwrap().constant = true;
+            } else {
+                self.globals.set_const(global);
+            }
+        }
         self.define_variable(global);
     }
 
@@ -425,6 +614,164 @@ impl<'a> Parser<'a> {
         self.patch_jump(else_jump);
     }
 
+    fn switch_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after switch value");
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch body");
+
+        // The subject stays on the stack while the cases are compared against it, and is
+        // popped before running a body, so it doesn't get in the way of the body's locals
+        let mut end_jumps = Vec::new();
+        let mut default_start = None;
+
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if self.match_token(TokenType::Case) {
+                self.emit_opcode(OpCode::Dup);
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after case value");
+                self.emit_opcode(OpCode::Equal);
+
+                let next_case = self.emit_jump(OpCode::JumpIfFalse);
+                // The comparison and the subject
+                self.emit_opcodes(OpCode::Pop, OpCode::Pop);
+                self.case_body();
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+
+                self.patch_jump(next_case);
+                self.emit_opcode(OpCode::Pop);
+            } else if self.match_token(TokenType::Default) {
+                if default_start.is_some() {
+                    self.error("A switch can only have one default case");
+                }
+                self.consume(TokenType::Colon, "Expect ':' after 'default'");
+
+                // The default case can be anywhere, but should only run after every case has
+                // been tried, so jump over it here and loop back to it at the end
+                let skip_default = self.emit_jump(OpCode::Jump);
+                default_start = Some(self.current_chunk().code.len());
+                self.case_body();
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(skip_default);
+            } else {
+                self.error_at_current("Expect 'case' or 'default' in switch body");
+                return;
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after switch body");
+
+        // No case matched
+        self.emit_opcode(OpCode::Pop);
+        if let Some(default_start) = default_start {
+            self.emit_loop(default_start);
+        }
+
+        for end_jump in end_jumps {
+            self.patch_jump(end_jump);
+        }
+    }
+
+    // The statements of a case run until the next case, like a block without braces
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::EOF)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value");
+        self.emit_opcode(OpCode::Throw);
+    }
+
+    // A throw in the try block unwinds the stack to where it was at `PushHandler`, and pushes
+    // the thrown value, which is then in the slot of the catch variable
+    fn try_statement(&mut self) {
+        let handler_jump = self.emit_jump(OpCode::PushHandler);
+        self.compiler.handlers += 1;
+
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+
+        self.compiler.handlers -= 1;
+        self.emit_opcode(OpCode::PopHandler);
+        let exit_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(handler_jump);
+
+        self.consume(TokenType::Catch, "Expect 'catch' after try block");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'");
+        self.begin_scope();
+        self.consume(TokenType::Identifier, "Expect error variable name");
+        self.declare_variable();
+        self.mark_initialized();
+        self.consume(TokenType::RightParen, "Expect ')' after error variable");
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch block");
+        self.block();
+        self.end_scope();
+
+        self.patch_jump(exit_jump);
+    }
+
+    fn break_statement(&mut self) {
+        let (loop_depth, loop_handlers) = match self.compiler.loops.last() {
+            Some(innermost) => (innermost.scope_depth, innermost.handlers),
+            None => {
+                self.error("Cannot use 'break' outside of a loop");
+                return;
+            }
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'");
+
+        // Discard the locals declared inside the loop body. They are still in scope for the
+        // compiler, so we only emit the pops and leave `locals` alone
+        let pops = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > loop_depth)
+            .count();
+        for _ in 0..pops {
+            self.emit_opcode(OpCode::Pop);
+        }
+        // As well as the handlers of any try blocks we break out of
+        for _ in loop_handlers..self.compiler.handlers {
+            self.emit_opcode(OpCode::PopHandler);
+        }
+
+        let jump = self.emit_jump(OpCode::Jump);
+        self.compiler
+            .loops
+            .last_mut()
+            .unwrap()
+            .break_jumps
+            .push(jump);
+    }
+
+    fn begin_loop(&mut self) {
+        self.compiler.loops.push(Loop {
+            scope_depth: self.compiler.scope_depth,
+            handlers: self.compiler.handlers,
+            break_jumps: Vec::new(),
+        });
+    }
+
+    fn end_loop(&mut self) {
+        let innermost = self.compiler.loops.pop().unwrap();
+        for jump in innermost.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after while");
@@ -434,10 +781,12 @@ impl<'a> Parser<'a> {
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
 
         self.emit_opcode(OpCode::Pop);
+        self.begin_loop();
         self.statement();
         self.emit_loop(loop_start);
         self.patch_jump(exit_jump);
         self.emit_opcode(OpCode::Pop);
+        self.end_loop();
     }
 
     fn for_statement(&mut self) {
@@ -447,7 +796,7 @@ impl<'a> Parser<'a> {
         if self.match_token(TokenType::Semicolon) {
             // No initializer
         } else if self.match_token(TokenType::Var) {
-            self.var_declaration();
+            self.var_declaration(false);
         } else {
             self.expression_statement();
         }
@@ -458,6 +807,8 @@ impl<'a> Parser<'a> {
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition");
             let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+            // Pop the condition before running the body
+            self.emit_opcode(OpCode::Pop);
             Some(exit_jump)
         } else {
             None
@@ -476,6 +827,7 @@ impl<'a> Parser<'a> {
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
+        self.begin_loop();
         self.statement();
 
         self.emit_loop(loop_start);
@@ -484,6 +836,7 @@ impl<'a> Parser<'a> {
             self.patch_jump(exit_jump);
             self.emit_opcode(OpCode::Pop);
         }
+        self.end_loop();
 
         self.end_scope();
     }
@@ -527,13 +880,17 @@ impl<'a> Parser<'a> {
 
             match self.current.typ {
                 TokenType::Class
+                | TokenType::Const
                 | TokenType::Fun
                 | TokenType::Var
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => return,
+                | TokenType::Return
+                | TokenType::Switch
+                | TokenType::Throw
+                | TokenType::Try => return,
 
                 _ => { /* Do nothing */ }
             }
@@ -608,10 +965,36 @@ impl<'a> Parser<'a> {
     }
 
     fn string(&mut self, _can_assign: bool) {
-        let constant = Value::Obj(
-            self.heap
-                .copy_string(&self.previous.str[1..self.previous.str.len() - 1]),
-        );
+        self.string_part(1);
+    }
+
+    // `"a${b}c${d}e"` is compiled as `"a" + b + "c" + d + "e"`, with `b` and `d` turned into
+    // strings first
+    fn interpolation(&mut self, _can_assign: bool) {
+        self.string_part(2);
+        loop {
+            self.expression();
+            self.emit_opcodes(OpCode::Stringify, OpCode::Add);
+
+            if self.match_token(TokenType::Interpolation) {
+                self.string_part(2);
+            } else if self.match_token(TokenType::String) {
+                self.string_part(1);
+                self.emit_opcode(OpCode::Add);
+                return;
+            } else {
+                self.error_at_current("Expect '}' after interpolated expression");
+                return;
+            }
+            self.emit_opcode(OpCode::Add);
+        }
+    }
+
+    // Emits the text of a `String` or `Interpolation` token, without the `"` or `}` it starts
+    // with and the `suffix_len` characters (`"` or `${`) it ends with
+    fn string_part(&mut self, suffix_len: usize) {
+        let text = &self.previous.str[1..self.previous.str.len() - suffix_len];
+        let constant = Value::Obj(self.heap.copy_string(&text.replace("\\$", "$")));
 
         self.emit_constant(constant);
     }
@@ -623,6 +1006,14 @@ impl<'a> Parser<'a> {
     fn named_variable(&mut self, name: Token, can_assign: bool) {
         let (local_arg, error) = self.compiler.resolve_local(name);
 
+        // Looking it up as a global would find a different variable, or none at all
+        if local_arg.is_none() {
+            if let (Some(_), _) = self.compiler.resolve_upvalue(name) {
+                self.error("Cannot capture local variables, closures are not supported yet");
+                return;
+            }
+        }
+
         // I try to make how we do error handling match how it's done in the book. However this is
         // an edge case where that is difficult because of borrowing, so we move the call to
         // self.error here
@@ -630,21 +1021,41 @@ impl<'a> Parser<'a> {
             self.error(error)
         }
 
-        let (arg, get_opt, set_opt) = if let Some(local_arg) = local_arg {
-            (local_arg, OpCode::GetLocal, OpCode::SetLocal)
-        } else {
-            (
-                self.identifier_constant(name),
-                OpCode::GetGlobal,
-                OpCode::SetGlobal,
-            )
+        let (arg, get_opt, set_opt) = match local_arg {
+            Some(local_arg) if local_arg > u8::MAX as u16 => {
+                (local_arg, OpCode::GetLocalLong, OpCode::SetLocalLong)
+            }
+            Some(local_arg) => (local_arg, OpCode::GetLocal, OpCode::SetLocal),
+            None => match self.global_slot(name) {
+                global if global > u8::MAX as u16 => {
+                    (global, OpCode::GetGlobalLong, OpCode::SetGlobalLong)
+                }
+                global => (global, OpCode::GetGlobal, OpCode::SetGlobal),
+            },
         };
 
-        if can_assign && self.match_token(TokenType::Equal) {
+        let op = if can_assign && self.match_token(TokenType::Equal) {
+            let constant = match local_arg {
+                Some(local_arg) => self.compiler.locals[local_arg as usize].constant,
+                None => self.globals.is_const(arg),
+            };
+            if constant {
+                self.error_at(name, "Cannot assign to a constant");
+            }
             self.expression();
-            self.emit_opcode_byte(set_opt, arg);
+            // Assignment is an expression, so keep a copy of the value on the stack
+            self.emit_opcode(OpCode::Dup);
+            set_opt
         } else {
-            self.emit_opcode_byte(get_opt, arg);
+            get_opt
+        };
+
+        match op {
+            OpCode::GetLocalLong
+            | OpCode::SetLocalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong => self.emit_opcode_short(op, arg),
+            _ => self.emit_opcode_byte(op, arg as u8),
         }
     }
 
@@ -737,6 +1148,12 @@ impl<'a> Parser<'a> {
         self.emit_byte(byte);
     }
 
+    fn emit_opcode_short(&mut self, opcode: OpCode, short: u16) {
+        self.emit_opcode(opcode);
+        self.emit_byte((short >> 8) as u8);
+        self.emit_byte((short & 0xff) as u8);
+    }
+
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
         self.emit_opcode_byte(OpCode::Constant, constant);
@@ -758,19 +1175,26 @@ impl<'a> Parser<'a> {
         self.error_at(self.previous, message);
     }
 
+    // Warnings are only printed, they don't stop the program from running
+    fn warning(&mut self, message: &str) {
+        eprintln!("[line {}] Warning: {}", self.previous.line, message);
+    }
+
     fn error_at(&mut self, token: Token, message: &str) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-        match token.typ {
-            TokenType::EOF => eprint!(" at end"),
-            TokenType::Error => {}
-            _ => eprint!(" at '{}'", token.str),
+        let location = match token.typ {
+            TokenType::EOF => Some(Location::End),
+            TokenType::Error => None,
+            _ => Some(Location::Token(token.str.to_owned())),
         };
-        eprintln!(": {}", message);
-        self.had_error = true;
+        self.errors.push(CompileError {
+            line: token.line,
+            location,
+            message: message.to_owned(),
+        });
     }
 }
 
@@ -894,6 +1318,11 @@ fn get_rule<'a>(typ: TokenType) -> ParseRule<'a> {
             infix: None,
             precedence: Precedence::None,
         },
+        Interpolation => ParseRule {
+            prefix: Some(Parser::interpolation),
+            infix: None,
+            precedence: Precedence::None,
+        },
         String => ParseRule {
             prefix: Some(Parser::string),
             infix: None,
@@ -909,11 +1338,56 @@ fn get_rule<'a>(typ: TokenType) -> ParseRule<'a> {
             infix: Some(Parser::and),
             precedence: Precedence::And,
         },
+        Break => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        Const => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
         Class => ParseRule {
             prefix: None,
             infix: None,
             precedence: Precedence::None,
         },
+        Colon => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        Switch => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        Default => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        Case => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        Catch => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        Throw => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        Try => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
         Else => ParseRule {
             prefix: None,
             infix: None,
@@ -1010,3 +1484,55 @@ struct ParseRule<'a> {
     infix: Option<ParserFn<'a>>,
     precedence: Precedence,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_returns_every_error() {
+        let mut heap = ObjHeap::new();
+        let mut globals = Globals::new();
+        let errors = match compile("var 1;\nprint (2;\nprint", &mut heap, &mut globals) {
+            Err(errors) => errors,
+            Ok(_) => panic!("Expected compile errors"),
+        };
+
+        let errors = errors
+            .iter()
+            .map(|error| (error.line, error.location.clone(), error.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    1,
+                    Some(Location::Token("1".to_owned())),
+                    "Expect variable name"
+                ),
+                (
+                    2,
+                    Some(Location::Token(";".to_owned())),
+                    "Expected ')' after expression"
+                ),
+                (3, Some(Location::End), "Expect expression"),
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_error_display() {
+        let error = CompileError {
+            line: 3,
+            location: Some(Location::Token("x".to_owned())),
+            message: "Bad".to_owned(),
+        };
+        assert_eq!(error.to_string(), "[line 3] Error at 'x': Bad");
+
+        let error = CompileError {
+            location: None,
+            ..error
+        };
+        assert_eq!(error.to_string(), "[line 3] Error: Bad");
+    }
+}
@@ -24,33 +24,51 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize, heap: &ObjHeap) ->
     }
     let instruction = chunk.code[offset].try_into();
 
-    match instruction {
+    let next = match instruction {
         Ok(instruction) => match instruction {
-            Constant | DefineGlobal | GetGlobal | SetGlobal => {
-                constant_instruction(instruction, chunk, offset, heap)
-            }
+            Constant => constant_instruction(instruction, chunk, offset, heap),
             Negate | Return | Add | Subtract | Multiply | Divide | Nil | True | False | Not
-            | Equal | Greater | Less | Print | Pop => simple_instruction(instruction, offset),
-            GetLocal | SetLocal | Call => byte_instruction(instruction, chunk, offset),
-            Jump | JumpIfFalse => jump_instruction(instruction, 1, chunk, offset),
+            | Equal | Greater | Less | Print | Pop | Dup | Stringify | PopHandler | Throw => {
+                simple_instruction(instruction, offset)
+            }
+            GetLocal | SetLocal | DefineGlobal | GetGlobal | SetGlobal | Call => {
+                byte_instruction(instruction, chunk, offset)
+            }
+            GetLocalLong | SetLocalLong | GetGlobalLong | DefineGlobalLong | SetGlobalLong => {
+                short_instruction(instruction, chunk, offset)
+            }
+            Jump | JumpIfFalse | PushHandler => jump_instruction(instruction, 1, chunk, offset),
             Loop => jump_instruction(instruction, -1, chunk, offset),
         },
         Err(err) => {
             println!("Unknown opcode: {}", err.number);
-            offset + 1
+            Some(offset + 1)
         }
-    }
+    };
+
+    // The chunk ends before the operands do, so there is nothing more to show
+    next.unwrap_or_else(|| {
+        println!("<truncated>");
+        chunk.code.len()
+    })
+}
+
+fn byte_instruction(instruction: OpCode, chunk: &Chunk, offset: usize) -> Option<usize> {
+    let slot = *chunk.code.get(offset + 1)?;
+    println!("{:16} {:4}", instruction, slot);
+
+    Some(offset + 2)
 }
 
-fn byte_instruction(instruction: OpCode, chunk: &Chunk, offset: usize) -> usize {
-    let slot = chunk.code[offset + 1];
+fn short_instruction(instruction: OpCode, chunk: &Chunk, offset: usize) -> Option<usize> {
+    let slot = u16::from_be_bytes([*chunk.code.get(offset + 1)?, *chunk.code.get(offset + 2)?]);
     println!("{:16} {:4}", instruction, slot);
 
-    offset + 2
+    Some(offset + 3)
 }
 
-fn jump_instruction(instruction: OpCode, sign: i32, chunk: &Chunk, offset: usize) -> usize {
-    let jump = (chunk.code[offset + 1] as u16) << 8 | chunk.code[offset + 2] as u16;
+fn jump_instruction(instruction: OpCode, sign: i32, chunk: &Chunk, offset: usize) -> Option<usize> {
+    let jump = u16::from_be_bytes([*chunk.code.get(offset + 1)?, *chunk.code.get(offset + 2)?]);
     println!(
         "{:16} {:4} -> {}",
         instruction,
@@ -58,7 +76,7 @@ fn jump_instruction(instruction: OpCode, sign: i32, chunk: &Chunk, offset: usize
         offset as i32 + 3 + sign * jump as i32
     );
 
-    offset + 3
+    Some(offset + 3)
 }
 
 fn constant_instruction(
@@ -66,19 +84,19 @@ fn constant_instruction(
     chunk: &Chunk,
     offset: usize,
     heap: &ObjHeap,
-) -> usize {
-    let constant = chunk.code[offset + 1];
+) -> Option<usize> {
+    let constant = *chunk.code.get(offset + 1)?;
     println!(
         "{:16} {:4} '{}'",
         instruction,
         constant,
-        chunk.constant(constant).to_string(heap)
+        chunk.constant(constant).debug_string(heap)
     );
 
-    offset + 2
+    Some(offset + 2)
 }
 
-fn simple_instruction(instruction: OpCode, offset: usize) -> usize {
+fn simple_instruction(instruction: OpCode, offset: usize) -> Option<usize> {
     println!("{}", instruction);
-    offset + 1
+    Some(offset + 1)
 }
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, convert::TryInto};
+
+use crate::{object::ObjPointer, value::Value};
+
+/// Global variables, stored in slots so the VM can index them directly instead of hashing
+/// the name on every access. The compiler hands out a slot the first time it sees a name,
+/// and the slot stays empty until the global is defined at runtime.
+pub struct Globals {
+    slots: HashMap<ObjPointer, u16>,
+    names: Vec<ObjPointer>,
+    values: Vec<Option<Value>>,
+    // Declared with `const`, so they can't be assigned to
+    constants: Vec<bool>,
+}
+
+impl Globals {
+    pub fn new() -> Globals {
+        Globals {
+            slots: HashMap::new(),
+            names: Vec::new(),
+            values: Vec::new(),
+            constants: Vec::new(),
+        }
+    }
+
+    /// Returns the slot for `name`, adding one if this is the first time it is seen. Returns
+    /// `None` if there is no room for more globals.
+    pub fn resolve(&mut self, name: ObjPointer) -> Option<u16> {
+        if let Some(slot) = self.slots.get(&name) {
+            return Some(*slot);
+        }
+
+        let slot: u16 = self.names.len().try_into().ok()?;
+        self.slots.insert(name, slot);
+        self.names.push(name);
+        self.values.push(None);
+        self.constants.push(false);
+        Some(slot)
+    }
+
+    #[inline]
+    pub fn get(&self, slot: u16) -> Option<Value> {
+        self.values[slot as usize]
+    }
+
+    #[inline]
+    pub fn is_defined(&self, slot: u16) -> bool {
+        self.values[slot as usize].is_some()
+    }
+
+    #[inline]
+    pub fn set(&mut self, slot: u16, value: Value) {
+        self.values[slot as usize] = Some(value);
+    }
+
+    #[inline]
+    pub fn is_const(&self, slot: u16) -> bool {
+        self.constants[slot as usize]
+    }
+
+    pub fn set_const(&mut self, slot: u16) {
+        self.constants[slot as usize] = true;
+    }
+
+    pub fn name(&self, slot: u16) -> ObjPointer {
+        self.names[slot as usize]
+    }
+
+    /// Every object the globals point to, including their names
+    pub fn objects(&self) -> impl Iterator<Item = ObjPointer> + '_ {
+        let values = self.values.iter().filter_map(|value| match value {
+            Some(Value::Obj(pointer)) => Some(*pointer),
+            _ => None,
+        });
+        self.names.iter().copied().chain(values)
+    }
+}
@@ -6,6 +6,7 @@ use vm::{InterpretError, VM};
 mod chunk;
 mod compiler;
 mod debug;
+mod globals;
 mod object;
 mod scanner;
 mod value;
@@ -41,23 +42,34 @@ mod vm;
 fn main() -> Result<()> {
     pretty_env_logger::init();
 
-    let args = std::env::args().collect::<Vec<_>>();
+    let mut args = std::env::args().collect::<Vec<_>>();
+
+    let mut vm = VM::new();
+    if let Some(index) = args
+        .iter()
+        .position(|arg| arg.starts_with("--max-instructions="))
+    {
+        let arg = args.remove(index);
+        match arg["--max-instructions=".len()..].parse() {
+            Ok(limit) => vm.set_instruction_limit(Some(limit)),
+            Err(_) => eprintln!("Invalid --max-instructions, ignoring it"),
+        }
+    }
 
     if args.len() == 1 {
-        repl()?;
+        repl(vm)?;
     } else if args.len() == 2 {
-        run_file(&args[1])?;
+        run_file(vm, &args[1])?;
     } else {
-        eprintln!("Usage: {} [path]\n", args[0]);
+        eprintln!("Usage: {} [--max-instructions=N] [path]\n", args[0]);
         std::process::exit(64);
     }
     Ok(())
 }
 
-fn repl() -> Result<()> {
+fn repl(mut vm: VM) -> Result<()> {
     let mut buffer = String::new();
     let mut stdout = std::io::stdout();
-    let mut vm = VM::new();
     let stdin = std::io::stdin();
     loop {
         stdout.write(b"> ")?;
@@ -75,26 +87,25 @@ fn repl() -> Result<()> {
         if let Err(err) = vm.interpret(&buffer) {
             eprintln!("{}", err);
         }
+        vm.gc_hint();
     }
 
     Ok(())
 }
 
-fn run_file(name: &str) -> Result<()> {
+fn run_file(mut vm: VM, name: &str) -> Result<()> {
     let mut file = std::fs::File::open(name)?;
     let mut buffer = String::new();
     file.read_to_string(&mut buffer)?;
 
-    let mut vm = VM::new();
     let result = vm.interpret(&buffer);
 
     if let Err(err) = result {
+        // Printed the same way as in the REPL
+        eprintln!("{}", err);
         match err {
-            InterpretError::CompileError => std::process::exit(65),
-            InterpretError::RuntimeError(inner) => {
-                eprintln!("Runtime Error: {}", inner);
-                std::process::exit(70)
-            }
+            InterpretError::CompileError(_) => std::process::exit(65),
+            InterpretError::RuntimeError(_) => std::process::exit(70),
         }
     }
 
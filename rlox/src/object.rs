@@ -1,10 +1,16 @@
 use crate::{chunk::Chunk, value::Value};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    rc::Rc,
+};
 
 #[derive(Clone)]
 pub struct ObjHeap {
     heap: Vec<Obj>,
-    strings: HashMap<String, ObjPointer>,
+    // The key shares its allocation with the `ObjKind::String` it points to, so interning
+    // doesn't keep a second copy of every string around
+    strings: HashMap<Rc<str>, ObjPointer>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Hash, Eq)]
@@ -15,18 +21,29 @@ pub struct Obj {
     pub kind: ObjKind,
 }
 
-pub type NativeFunction = fn(&[Value]) -> Value;
+// An `Err` is turned into a runtime error at the call site
+pub type NativeFunction = fn(&[Value], &mut ObjHeap) -> Result<Value, String>;
 
 #[derive(Clone)]
 pub enum ObjKind {
-    String(String),
+    String(Rc<str>),
     Function(ObjFunction),
-    NativeFunction(NativeFunction),
+    NativeFunction(ObjNative),
+}
+
+#[derive(Clone)]
+pub struct ObjNative {
+    pub function: NativeFunction,
+    // Checked before the native is called, so it gets the number of arguments it expects. Use
+    // `n..=usize::MAX` for natives taking any number of arguments
+    pub arity: RangeInclusive<usize>,
 }
 
 #[derive(Clone, PartialEq)]
 pub struct ObjFunction {
     pub arity: usize,
+    // The most locals in scope at once, including the slot for the function itself
+    pub max_slots: usize,
     pub chunk: Chunk,
     pub name: Option<String>,
 }
@@ -47,13 +64,14 @@ impl ObjHeap {
     }
 
     pub fn take_string(&mut self, str: String) -> ObjPointer {
-        if let Some(interned) = self.strings.get(&str) {
+        if let Some(interned) = self.strings.get(str.as_str()) {
             return *interned;
         }
         self.allocate_string(str)
     }
 
     fn allocate_string(&mut self, str: String) -> ObjPointer {
+        let str: Rc<str> = str.into();
         let ptr = self.allocate_obj(ObjKind::String(str.clone()));
         self.strings.insert(str, ptr);
         ptr
@@ -63,6 +81,48 @@ impl ObjHeap {
         self.heap.push(Obj { kind });
         ObjPointer(self.heap.len() - 1)
     }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// There is no garbage collector yet, and objects are addressed by their index, so we can't
+    /// move objects around. What we can do is to drop the objects after the last one that is
+    /// reachable from `roots`. Garbage below that object is kept.
+    ///
+    /// This is only safe when `roots` contains every pointer the VM holds on to, so nothing
+    /// on the stack and no compiler in progress.
+    pub fn free_unreachable_tail(&mut self, roots: impl Iterator<Item = ObjPointer>) {
+        let mut reachable = HashSet::new();
+        let mut gray = roots.collect::<Vec<_>>();
+
+        while let Some(pointer) = gray.pop() {
+            if !reachable.insert(pointer) {
+                continue;
+            }
+
+            if let ObjKind::Function(function) = &pointer.borrow(self).kind {
+                for constant in function.chunk.constants() {
+                    if let Value::Obj(pointer) = constant {
+                        gray.push(*pointer);
+                    }
+                }
+            }
+        }
+
+        let len = reachable
+            .iter()
+            .map(|pointer| pointer.0 + 1)
+            .max()
+            .unwrap_or(0);
+
+        for obj in &self.heap[len..] {
+            if let ObjKind::String(string) = &obj.kind {
+                self.strings.remove(string);
+            }
+        }
+        self.heap.truncate(len);
+    }
 }
 
 impl ObjPointer {
@@ -71,14 +131,20 @@ impl ObjPointer {
     }
 
     pub fn to_string(&self, heap: &ObjHeap) -> String {
-        format!("{} ({})", self.borrow(heap).to_string(), self.0)
+        self.borrow(heap).to_string()
+    }
+
+    /// Includes the heap index, for the disassembler and execution trace
+    #[allow(dead_code)]
+    pub fn debug_string(&self, heap: &ObjHeap) -> String {
+        format!("{} ({})", self.to_string(heap), self.0)
     }
 }
 
 impl Obj {
     pub fn to_string(&self) -> String {
         match &self.kind {
-            ObjKind::String(inner) => inner.clone(),
+            ObjKind::String(inner) => inner.to_string(),
             ObjKind::Function(inner) => {
                 format!("<fn {}>", inner.name.as_deref().unwrap_or("<script>"))
             }
@@ -104,8 +170,26 @@ impl ObjFunction {
     pub fn new() -> ObjFunction {
         ObjFunction {
             arity: 0,
+            max_slots: 1,
             name: None,
             chunk: Chunk::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_strings_are_allocated_once() {
+        let mut heap = ObjHeap::new();
+        let first = heap.copy_string("interned");
+
+        for _ in 0..100 {
+            assert_eq!(heap.copy_string("interned"), first);
+            assert_eq!(heap.take_string("interned".to_owned()), first);
+        }
+        assert_eq!(heap.len(), 1);
+    }
+}
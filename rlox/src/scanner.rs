@@ -1,8 +1,12 @@
 pub struct Scanner<'a> {
     start: &'a str,
-    // How many characters into "start"" are we currently
+    // How many bytes into "start" are we currently. Always at a char boundary, so "start" can be
+    // sliced with it
     current: usize,
     line: usize,
+    // One entry for each `${` we are inside of, counting the `{` opened since then. When a `}`
+    // is found with the count at zero, it ends the interpolation and the string continues
+    interpolations: Vec<usize>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -19,6 +23,7 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -39,12 +44,19 @@ pub enum TokenType {
 
     // Literals.
     Identifier,
+    // The part of a string up to a `${`, the expression and the rest of the string follows
+    Interpolation,
     String,
     Number,
 
     // Keywords.
     And,
+    Break,
+    Case,
+    Catch,
     Class,
+    Const,
+    Default,
     Else,
     False,
     Fun,
@@ -55,8 +67,11 @@ pub enum TokenType {
     Print,
     Return,
     Super,
+    Switch,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -77,6 +92,7 @@ impl<'a> Scanner<'a> {
             start: source,
             current: 0,
             line: 1,
+            interpolations: Vec::new(),
         }
     }
 
@@ -97,9 +113,24 @@ impl<'a> Scanner<'a> {
         match c {
             '(' => self.make_token(LeftParen),
             ')' => self.make_token(RightParen),
-            '{' => self.make_token(LeftBrace),
-            '}' => self.make_token(RightBrace),
+            '{' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                self.make_token(LeftBrace)
+            }
+            '}' if self.interpolations.last() == Some(&0) => {
+                self.interpolations.pop();
+                self.string()
+            }
+            '}' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces -= 1;
+                }
+                self.make_token(RightBrace)
+            }
             ';' => self.make_token(Semicolon),
+            ':' => self.make_token(Colon),
             ',' => self.make_token(Comma),
             '.' => self.make_token(Dot),
             '-' => self.make_token(Minus),
@@ -138,25 +169,48 @@ impl<'a> Scanner<'a> {
     fn identifier_type(&self) -> TokenType {
         match self.char_at(0) {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
+            'd' => self.check_keyword(1, 6, "efault", TokenType::Default),
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'i' => self.check_keyword(1, 1, "f", TokenType::If),
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
             'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
             'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
             'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+            'c' if self.current > 1 => match self.char_at(1) {
+                'a' if self.current > 2 => match self.char_at(2) {
+                    's' => self.check_keyword(3, 1, "e", TokenType::Case),
+                    't' => self.check_keyword(3, 2, "ch", TokenType::Catch),
+                    _ => TokenType::Identifier,
+                },
+                'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                'o' => self.check_keyword(2, 3, "nst", TokenType::Const),
+                _ => TokenType::Identifier,
+            },
             'f' if self.current > 1 => match self.char_at(1) {
                 'a' => self.check_keyword(2, 3, "lse", TokenType::False),
                 'o' => self.check_keyword(2, 1, "r", TokenType::For),
                 'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
                 _ => TokenType::Identifier,
             },
+            's' if self.current > 1 => match self.char_at(1) {
+                'u' => self.check_keyword(2, 3, "per", TokenType::Super),
+                'w' => self.check_keyword(2, 4, "itch", TokenType::Switch),
+                _ => TokenType::Identifier,
+            },
             't' if self.current > 1 => match self.char_at(1) {
-                'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                'r' => self.check_keyword(2, 2, "ue", TokenType::True),
+                'h' if self.current > 2 => match self.char_at(2) {
+                    'i' => self.check_keyword(3, 1, "s", TokenType::This),
+                    'r' => self.check_keyword(3, 2, "ow", TokenType::Throw),
+                    _ => TokenType::Identifier,
+                },
+                'r' if self.current > 2 => match self.char_at(2) {
+                    'u' => self.check_keyword(3, 1, "e", TokenType::True),
+                    'y' => self.check_keyword(3, 0, "", TokenType::Try),
+                    _ => TokenType::Identifier,
+                },
                 _ => TokenType::Identifier,
             },
             _ => TokenType::Identifier,
@@ -189,10 +243,23 @@ impl<'a> Scanner<'a> {
         self.make_token(TokenType::Number)
     }
 
+    // Scans the rest of a string, after the opening quote or the `}` ending an interpolated
+    // expression
     fn string(&mut self) -> Token<'a> {
         while self.peek() != Some('"') && !self.is_at_end() {
-            if self.peek() == Some('\n') {
-                self.line += 1;
+            match self.peek() {
+                Some('\n') => self.line += 1,
+                // `\$` is a literal dollar, the compiler removes the backslash
+                Some('\\') if self.peek_next() == Some('$') => {
+                    self.advance();
+                }
+                Some('$') if self.peek_next() == Some('{') => {
+                    self.advance();
+                    self.advance();
+                    self.interpolations.push(0);
+                    return self.make_token(TokenType::Interpolation);
+                }
+                _ => {}
             }
             self.advance();
         }
@@ -238,22 +305,20 @@ impl<'a> Scanner<'a> {
         }
     }
     fn peek_next(&self) -> Option<char> {
-        if self.is_at_end() {
-            None
-        } else {
-            Some(self.char_at(self.current + 1))
-        }
+        self.start[self.current..].chars().nth(1)
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.char_at(self.current - 1)
+        let c = self.char_at(self.current);
+        self.current += c.len_utf8();
+        c
     }
 
+    // `idx` is a byte index, and has to be at a char boundary
     fn char_at(&self, idx: usize) -> char {
-        self.start
+        self.start[idx..]
             .chars()
-            .nth(idx)
+            .next()
             .expect("char_at called with out of index number")
     }
 
@@ -295,8 +360,10 @@ fn op_is_digit(op: Option<char>) -> bool {
     }
 }
 
+// Identifiers can use letters from any language. Keywords are all ASCII, so `identifier_type`
+// can keep matching them byte by byte
 fn is_alpha(c: char) -> bool {
-    c.is_ascii_alphabetic() || c == '_'
+    c.is_alphabetic() || c == '_'
 }
 
 fn check_op(fun: fn(char) -> bool, op: Option<char>) -> bool {
@@ -1,4 +1,4 @@
-use crate::object::{ObjHeap, ObjPointer};
+use crate::object::{ObjHeap, ObjKind, ObjPointer};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Value {
@@ -17,6 +17,18 @@ impl Value {
         }
     }
 
+    /// The number as an index or a count, if it is a non-negative whole number. There is only
+    /// one number type, so instructions taking an index should check it here
+    #[allow(dead_code)]
+    pub fn as_index(&self) -> Option<usize> {
+        match self {
+            Value::Number(number) if *number >= 0. && number.fract() == 0. => {
+                Some(*number as usize)
+            }
+            _ => None,
+        }
+    }
+
     pub fn to_string(&self, heap: &ObjHeap) -> String {
         match self {
             Value::Number(value) => format!("{}", value),
@@ -26,21 +38,68 @@ impl Value {
         }
     }
 
-    pub fn eq(&self, other: &Value) -> bool {
+    /// Like `to_string`, but objects include their heap index
+    #[allow(dead_code)]
+    pub fn debug_string(&self, heap: &ObjHeap) -> String {
+        match self {
+            Value::Obj(pointer) => pointer.debug_string(heap),
+            _ => self.to_string(heap),
+        }
+    }
+
+    pub fn eq(&self, other: &Value, heap: &ObjHeap) -> bool {
         match (self, other) {
             (Value::Nil, Value::Nil) => true,
             (Value::Number(a), Value::Number(b)) => a == b,
             (Value::Bool(a), Value::Bool(b)) => a == b,
-            (Value::Obj(a), Value::Obj(b)) => a == b,
+            (Value::Obj(a), Value::Obj(b)) if a == b => true,
+            (Value::Obj(a), Value::Obj(b)) => match (&a.borrow(heap).kind, &b.borrow(heap).kind) {
+                // Strings are interned, so this should not be needed, but don't rely on it for
+                // correctness
+                (ObjKind::String(a), ObjKind::String(b)) => a == b,
+                // Functions are only equal to themselves, even with the same name and code. New
+                // kinds of objects have to be added here
+                (ObjKind::Function(_), _) | (ObjKind::NativeFunction(_), _) => false,
+                (ObjKind::String(_), _) => false,
+            },
             _ => false,
         }
     }
 
-    #[inline]
-    pub fn as_obj_ptr(&self) -> ObjPointer {
+    pub fn to_owned_value(self, heap: &ObjHeap) -> OwnedValue {
         match self {
-            Value::Obj(res) => *res,
-            _ => panic!("Tried to cast non-obj-ptr value to obj-ptr"),
+            Value::Nil => OwnedValue::Nil,
+            Value::Number(value) => OwnedValue::Number(value),
+            Value::Bool(value) => OwnedValue::Bool(value),
+            Value::Obj(pointer) => match &pointer.borrow(heap).kind {
+                ObjKind::String(inner) => OwnedValue::String(inner.to_string()),
+                _ => OwnedValue::Object(pointer.borrow(heap).to_string()),
+            },
         }
     }
 }
+
+/// A value that does not point into the `ObjHeap`, so it can be handed out of the VM.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OwnedValue {
+    Nil,
+    Number(f64),
+    Bool(bool),
+    String(String),
+    // Any other object, by its printed representation
+    Object(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn as_index_takes_non_negative_whole_numbers() {
+        assert_eq!(Value::Number(3.0).as_index(), Some(3));
+        assert_eq!(Value::Number(3.5).as_index(), None);
+        assert_eq!(Value::Number(-1.0).as_index(), None);
+        assert_eq!(Value::Bool(true).as_index(), None);
+        assert_eq!(Value::Nil.as_index(), None);
+    }
+}
@@ -1,23 +1,43 @@
-use std::{collections::HashMap, convert::TryFrom};
+use std::{
+    convert::TryFrom,
+    ops::RangeInclusive,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+#[cfg(feature = "trace-execution")]
+use crate::debug::disassemble_instruction;
 use crate::{
     chunk::OpCode,
-    compiler::compile,
-    object::{NativeFunction, ObjFunction, ObjHeap, ObjKind, ObjPointer},
-    value::Value,
+    compiler::{compile, compile_expression, CompileError},
+    globals::Globals,
+    object::{NativeFunction, ObjFunction, ObjHeap, ObjKind, ObjNative, ObjPointer},
+    value::{OwnedValue, Value},
 };
+use log::debug;
 
 static START_TIME: parking_lot::RwLock<Option<std::time::Instant>> =
     parking_lot::const_rwlock(None);
 const FRAMES_MAX: usize = 64;
 const STACK_MAX: usize = FRAMES_MAX * 0xff;
+// The room every frame gets on the value stack, for its locals and temporaries. Functions with
+// more locals than this get room for all of them instead
+const FRAME_SLOTS_MAX: usize = 256;
 
 pub struct VM {
     frames: Vec<CallFrame>,
     stack: [Value; STACK_MAX],
     stack_top: usize,
     heap: ObjHeap,
-    globals: HashMap<ObjPointer, Value>,
+    globals: Globals,
+    // The try blocks we are in, innermost last
+    handlers: Vec<Handler>,
+    // How many instructions each `run` may execute before it's stopped. `u64::MAX` when there
+    // is no limit, which is never reached, so the check stays cheap
+    instruction_limit: u64,
+    instructions_left: u64,
+    // How many times each opcode has been executed in the current `run`
+    #[cfg(feature = "trace-execution")]
+    instruction_counts: [usize; 256],
 }
 
 pub struct CallFrame {
@@ -29,6 +49,13 @@ pub struct CallFrame {
     fp: usize,
 }
 
+// Where to continue when a value is thrown, and the frames and stack to go back to
+struct Handler {
+    frame_count: usize,
+    stack_top: usize,
+    catch_ip: usize,
+}
+
 impl CallFrame {
     fn function<'a>(&self, heap: &'a ObjHeap) -> &'a ObjFunction {
         self.function.borrow(heap).as_function()
@@ -37,14 +64,22 @@ impl CallFrame {
 
 #[derive(Debug)]
 pub enum InterpretError {
-    CompileError,
+    CompileError(Vec<CompileError>),
     RuntimeError(RuntimeError),
 }
 
 impl std::fmt::Display for InterpretError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            InterpretError::CompileError => write!(f, "Compile Error"),
+            InterpretError::CompileError(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
             InterpretError::RuntimeError(inner) => write!(f, "Runtime Error: {}", inner),
         }
     }
@@ -58,12 +93,14 @@ pub struct RuntimeError {
     call_stack: Vec<(usize, String)>,
 }
 
+// The message, then a line for each frame on the call stack. There's no "Runtime Error" prefix,
+// `InterpretError` adds that, and no trailing newline
 impl std::fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{}", self.message)?;
+        write!(f, "{}", self.message)?;
 
         for (line, name) in &self.call_stack {
-            writeln!(f, "[line {} in {}]", line, name)?;
+            write!(f, "\n[line {} in {}]", line, name)?;
         }
 
         Ok(())
@@ -89,8 +126,8 @@ macro_rules! binary_op {
     ($vm: expr, $valueType:expr, $op:tt) => {
         {
             use Value::*;
-            let b = $vm.pop();
-            let a = $vm.pop();
+            let b = $vm.pop()?;
+            let a = $vm.pop()?;
             match (a, b) {
                 (Number(a), Number(b)) => {
                     $vm.push($valueType(a $op b));
@@ -114,22 +151,31 @@ impl VM {
             stack_top: 0,
             frames: Vec::with_capacity(FRAMES_MAX),
             heap: ObjHeap::new(),
-            globals: HashMap::new(),
+            globals: Globals::new(),
+            handlers: Vec::new(),
+            instruction_limit: u64::MAX,
+            instructions_left: u64::MAX,
+            #[cfg(feature = "trace-execution")]
+            instruction_counts: [0; 256],
         };
 
-        vm.define_native("clock", clockNative);
+        vm.define_native("clock", clockNative, 0..=0);
+        vm.define_native("assert", assertNative, 1..=2);
+        vm.define_native("write", writeNative, 1..=1);
 
         vm
     }
 
-    pub fn generate_call_stack(&mut self) -> Vec<(usize, String)> {
+    pub fn generate_call_stack(&self) -> Vec<(usize, String)> {
         self.frames
             .iter()
             .rev()
             .map(|frame| {
                 let function = frame.function(&self.heap);
+                // Clamped, since a corrupt jump can leave ip past the end of the chunk
+                let offset = (frame.ip - 1).min(function.chunk.code.len() - 1);
                 (
-                    function.chunk.line(frame.ip - 1),
+                    function.chunk.line(offset),
                     function
                         .name
                         .as_ref()
@@ -140,28 +186,74 @@ impl VM {
             .collect()
     }
 
+    fn reset_stack(&mut self) {
+        self.stack_top = 0;
+        self.frames.clear();
+        self.handlers.clear();
+    }
+
+    /// Frees what it can of the objects allocated by earlier runs. Everything reachable from
+    /// the globals is kept. Until there is a real garbage collector, this is meant to be
+    /// called between lines in the REPL, so the heap doesn't grow with every temporary value.
+    pub fn gc_hint(&mut self) {
+        let before = self.heap.len();
+        self.heap.free_unreachable_tail(self.globals.objects());
+        debug!("gc_hint: heap {} -> {} objects", before, self.heap.len());
+    }
+
+    // How many values are on the stack, for tests checking that instructions keep it balanced
+    #[cfg(test)]
+    fn stack_len(&self) -> usize {
+        self.stack_top
+    }
+
     fn push(&mut self, value: Value) {
         self.stack[self.stack_top] = value;
         self.stack_top += 1;
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack_top -= 1;
-        self.stack[self.stack_top]
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.stack_top = self.slot_from_top(0)?;
+        Ok(self.stack[self.stack_top])
+    }
+
+    fn peek(&self, distance: usize) -> Result<Value, RuntimeError> {
+        Ok(self.stack[self.slot_from_top(distance)?])
+    }
+
+    // For instructions that replace the top value, without a pop and push
+    fn top_mut(&mut self) -> Result<&mut Value, RuntimeError> {
+        let slot = self.slot_from_top(0)?;
+        Ok(&mut self.stack[slot])
     }
 
-    fn peek(&self, distance: usize) -> Value {
-        self.stack[self.stack_top - 1 - distance]
+    // The compiler never emits code that uses more values than it has pushed, so running out is a
+    // bug in the compiler. Report it as a runtime error instead of reading garbage below the stack
+    fn slot_from_top(&self, distance: usize) -> Result<usize, RuntimeError> {
+        match self.stack_top.checked_sub(1 + distance) {
+            Some(slot) => Ok(slot),
+            None => runtime_error!(self, "Corrupt bytecode: stack underflow"),
+        }
     }
 
-    fn define_native(&mut self, name: &str, function: NativeFunction) {
+    fn define_native(
+        &mut self,
+        name: &str,
+        function: NativeFunction,
+        arity: RangeInclusive<usize>,
+    ) {
         let string = self.heap.copy_string(name);
         self.push(Value::Obj(string));
-        let function = Value::Obj(self.heap.allocate_obj(ObjKind::NativeFunction(function)));
+        let native = ObjNative { function, arity };
+        let function = Value::Obj(self.heap.allocate_obj(ObjKind::NativeFunction(native)));
         self.push(function);
-        self.globals.insert(string, function);
-        self.pop();
-        self.pop();
+        let slot = self
+            .globals
+            .resolve(string)
+            .expect("Natives are defined before any other globals");
+        self.globals.set(slot, function);
+        // Pop the name and the native
+        self.stack_top -= 2;
     }
 
     fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
@@ -171,10 +263,34 @@ impl VM {
                     let arity = function.arity;
                     self.call(callee_ptr, arg_count, arity)?;
                 }
-                ObjKind::NativeFunction(function) => {
-                    let result = function(&self.stack[self.stack_top..self.stack_top + arg_count]);
-                    self.stack_top = self.stack_top - arg_count;
-                    self.push(result);
+                ObjKind::NativeFunction(native) => {
+                    let function = native.function;
+                    if !native.arity.contains(&arg_count) {
+                        let arity = native.arity.clone();
+                        let expected = if arity.start() == arity.end() {
+                            format!("{}", arity.start())
+                        } else if *arity.end() == usize::MAX {
+                            format!("at least {}", arity.start())
+                        } else {
+                            format!("{} to {}", arity.start(), arity.end())
+                        };
+                        runtime_error!(
+                            self,
+                            "Expected {} arguments, but got {}",
+                            expected,
+                            arg_count
+                        );
+                    }
+                    let args_start = self.stack_top - arg_count;
+                    let result = function(&self.stack[args_start..self.stack_top], &mut self.heap);
+                    match result {
+                        Ok(result) => {
+                            // Pop the arguments and the native itself
+                            self.stack_top = args_start - 1;
+                            self.push(result);
+                        }
+                        Err(message) => runtime_error!(self, "{}", message),
+                    }
                 }
                 _ => runtime_error!(self, "Can only call functions and classes"),
             },
@@ -194,53 +310,89 @@ impl VM {
             runtime_error!(self, "Expected {} arguments, but got {}", arity, arg_count);
         }
 
-        if self.frames.len() == FRAMES_MAX {
+        let fp = self.stack_top - arg_count - 1;
+        let slots = function
+            .borrow(&self.heap)
+            .as_function()
+            .max_slots
+            .max(FRAME_SLOTS_MAX);
+
+        // Check that all the locals of the new frame will fit on the value stack, not only
+        // the arguments that are already there
+        if self.frames.len() == FRAMES_MAX || fp + slots > STACK_MAX {
             runtime_error!(self, "Stack overflow!");
         }
 
         self.frames.push(CallFrame {
             function,
             ip: 0,
-            fp: self.stack_top - arg_count - 1,
+            fp,
         });
 
         Ok(())
     }
 
+    // A compiler bug could make us read past the end of the chunk, for example by jumping too
+    // far. Report that as a runtime error instead of panicking.
     #[inline]
-    fn read_byte(&mut self) -> u8 {
-        // let res = self.chunk.code[self.ip];
-        let mut frame = frame!(self);
-        let res = frame.function(&mut self.heap).chunk.code[frame.ip];
-        frame.ip += 1;
-        res
+    fn read_byte(&mut self) -> Result<u8, RuntimeError> {
+        let frame = frame!(self);
+        let byte = frame.function(&self.heap).chunk.code.get(frame.ip).copied();
+        match byte {
+            Some(byte) => {
+                frame.ip += 1;
+                Ok(byte)
+            }
+            None => runtime_error!(self, "Corrupt bytecode: ip out of range"),
+        }
     }
 
     #[inline]
-    fn read_short(&mut self) -> u16 {
-        let frame = frame!(self);
-        let function = frame.function(&mut self.heap);
-        frame.ip += 2;
-        (function.chunk.code[frame.ip - 2] as u16) << 8 | (function.chunk.code[frame.ip - 1] as u16)
+    fn read_short(&mut self) -> Result<u16, RuntimeError> {
+        let high = self.read_byte()?;
+        let low = self.read_byte()?;
+        Ok((high as u16) << 8 | low as u16)
     }
 
+    // The long global opcodes have a two byte slot, the others one byte
     #[inline]
-    fn read_constant(&mut self) -> &Value {
-        let constant_id = self.read_byte();
-        let frame = frame!(self);
-        let function = frame.function(&mut self.heap);
-        function.chunk.constant(constant_id)
+    fn read_global_slot(&mut self, instruction: OpCode) -> Result<u16, RuntimeError> {
+        match instruction {
+            OpCode::GetGlobalLong | OpCode::DefineGlobalLong | OpCode::SetGlobalLong => {
+                self.read_short()
+            }
+            _ => Ok(self.read_byte()? as u16),
+        }
     }
 
     #[inline]
-    fn read_string(&mut self) -> ObjPointer {
-        self.read_constant().as_obj_ptr()
+    fn read_constant(&mut self) -> Result<&Value, RuntimeError> {
+        let constant_id = self.read_byte()?;
+        let frame = frame!(self);
+        let function = frame.function(&self.heap);
+        Ok(function.chunk.constant(constant_id))
     }
 
     pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
-        let function =
-            compile(source, &mut self.heap).map_err(|()| InterpretError::CompileError)?;
+        let function = compile(source, &mut self.heap, &mut self.globals)
+            .map_err(InterpretError::CompileError)?;
 
+        self.run_function(function)?;
+        Ok(())
+    }
+
+    /// Evaluates `source` as a single expression and returns its value. Meant for embedding
+    /// the VM, where `print` isn't a useful way of getting results out.
+    #[allow(dead_code)]
+    pub fn eval(&mut self, source: &str) -> Result<OwnedValue, InterpretError> {
+        let function = compile_expression(source, &mut self.heap, &mut self.globals)
+            .map_err(InterpretError::CompileError)?;
+
+        let result = self.run_function(function)?;
+        Ok(result.to_owned_value(&self.heap))
+    }
+
+    fn run_function(&mut self, function: ObjFunction) -> Result<Value, InterpretError> {
         let function = self.heap.allocate_obj(ObjKind::Function(function));
         let function = Value::Obj(function);
 
@@ -248,35 +400,145 @@ impl VM {
         self.call_value(function, 0)
             .map_err(InterpretError::RuntimeError)?;
 
-        self.run().map_err(InterpretError::RuntimeError)
+        let result = self.run().map_err(|err| {
+            self.reset_stack();
+            InterpretError::RuntimeError(err)
+        })?;
+
+        // The final Return pops the script function as well, so anything left is a value some
+        // instruction forgot to pop
+        debug_assert_eq!(self.stack_top, 0, "Values left on the stack after running");
+
+        Ok(result)
     }
 
-    pub fn run(&mut self) -> Result<(), RuntimeError> {
+    /// Stops scripts that run for more than `limit` instructions with a runtime error, so
+    /// untrusted code can't hang the host. `None` removes the limit.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit.unwrap_or(u64::MAX);
+    }
+
+    pub fn run(&mut self) -> Result<Value, RuntimeError> {
         *START_TIME.write() = Some(std::time::Instant::now());
+        self.instructions_left = self.instruction_limit;
+
+        #[cfg(feature = "trace-execution")]
+        {
+            self.instruction_counts = [0; 256];
+        }
+
+        let result = loop {
+            match self.run_loop() {
+                Err(error) if self.catch(&error) => continue,
+                result => break result,
+            }
+        };
+
+        #[cfg(feature = "trace-execution")]
+        self.print_instruction_summary();
+
+        result
+    }
+
+    #[cfg(feature = "trace-execution")]
+    fn print_instruction_summary(&self) {
+        let elapsed = START_TIME.read().unwrap().elapsed();
+
+        let mut counts = self
+            .instruction_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .collect::<Vec<_>>();
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+
+        println!("== instruction summary ==");
+        for (opcode, count) in counts {
+            match OpCode::try_from(opcode as u8) {
+                Ok(opcode) => println!("{:16} {:10}", opcode, count),
+                Err(_) => println!("{:16} {:10}", format!("Unknown {}", opcode), count),
+            }
+        }
+        println!(
+            "{} instructions in {:.3} ms",
+            self.instruction_counts.iter().sum::<usize>(),
+            elapsed.as_secs_f64() * 1000.
+        );
+    }
+
+    // Runtime errors are caught by the innermost try, like thrown values, with the message as the
+    // caught value. Running out of instructions can't be caught, or a script could catch its way
+    // past the limit
+    fn catch(&mut self, error: &RuntimeError) -> bool {
+        if self.instructions_left == 0 {
+            return false;
+        }
+        let handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => return false,
+        };
+
+        let message = Value::Obj(self.heap.copy_string(&error.message));
+        self.unwind(handler, message);
+        true
+    }
+
+    // Goes back to the frame and stack the try block started with, and continues in its catch
+    // block with `value` on the stack
+    fn unwind(&mut self, handler: Handler, value: Value) {
+        self.frames.truncate(handler.frame_count);
+        self.stack_top = handler.stack_top;
+        frame!(self).ip = handler.catch_ip;
+        self.push(value);
+    }
 
+    fn run_loop(&mut self) -> Result<Value, RuntimeError> {
         loop {
-            #[cfg(feature = "print-code")]
+            #[cfg(feature = "trace-execution")]
             {
                 print!("          ");
                 for i in 0..self.stack_top {
-                    print!("[ {} ]", self.stack[i].to_string(&self.heap));
+                    print!("[ {} ]", self.stack[i].debug_string(&self.heap));
                 }
                 println!();
-                let chunk = &frame!(self).function(&mut self.heap).chunk.clone();
-                disassemble_instruction(chunk, frame!(self).ip, &self.heap);
+                // Only shared borrows, so the chunk can be read in place while the heap is used
+                // to print constants
+                let frame = self.frames.last().unwrap();
+                let chunk = &frame.function(&self.heap).chunk;
+                // Past the end is a runtime error from `read_byte` below
+                if frame.ip < chunk.code.len() {
+                    disassemble_instruction(chunk, frame.ip, &self.heap);
+                }
+            }
+
+            let byte = self.read_byte()?;
+
+            #[cfg(feature = "trace-execution")]
+            {
+                self.instruction_counts[byte as usize] += 1;
+            }
+
+            if self.instructions_left == 0 {
+                runtime_error!(self, "Execution limit exceeded");
             }
+            self.instructions_left -= 1;
 
-            let instruction = OpCode::try_from(self.read_byte());
+            let instruction = OpCode::try_from(byte);
 
             match instruction {
                 Ok(instruction) => match instruction {
                     OpCode::Return => {
-                        let result = self.pop();
+                        let result = self.pop()?;
                         let frame = self.frames.pop().unwrap();
+                        // Returning from inside a try block leaves its handler behind
+                        while matches!(self.handlers.last(), Some(handler) if handler.frame_count > self.frames.len())
+                        {
+                            self.handlers.pop();
+                        }
 
                         if self.frames.is_empty() {
-                            self.pop();
-                            return Ok(());
+                            self.pop()?;
+                            return Ok(result);
                         }
 
                         self.stack_top = frame.fp;
@@ -284,16 +546,14 @@ impl VM {
                         self.push(result);
                     }
                     OpCode::Constant => {
-                        let constant = *self.read_constant();
+                        let constant = *self.read_constant()?;
                         self.push(constant);
                     }
-                    OpCode::Negate => match self.pop() {
-                        Value::Number(value) => self.push(Value::Number(-value)),
-                        operand => {
-                            runtime_error!(self, "Operand ({:?}) must be a number", operand);
-                        }
+                    OpCode::Negate => match self.top_mut()? {
+                        Value::Number(value) => *value = -*value,
+                        _ => runtime_error!(self, "Operand must be a number."),
                     },
-                    OpCode::Add => match (self.pop(), self.pop()) {
+                    OpCode::Add => match (self.pop()?, self.pop()?) {
                         (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a + b)),
                         (Value::Obj(b), Value::Obj(a)) => {
                             let new_obj =
@@ -320,80 +580,146 @@ impl VM {
                     OpCode::Nil => self.push(Value::Nil),
                     OpCode::True => self.push(Value::Bool(true)),
                     OpCode::False => self.push(Value::Bool(false)),
+                    OpCode::Stringify => {
+                        let value = self.pop()?;
+                        let string = match value {
+                            Value::Obj(pointer) => match &pointer.borrow(&self.heap).kind {
+                                ObjKind::String(_) => None,
+                                _ => Some(pointer.borrow(&self.heap).to_string()),
+                            },
+                            _ => Some(value.to_string(&self.heap)),
+                        };
+                        match string {
+                            Some(string) => {
+                                let string = self.heap.take_string(string);
+                                self.push(Value::Obj(string));
+                            }
+                            None => self.push(value),
+                        }
+                    }
                     OpCode::Not => {
-                        let value = Value::Bool(self.pop().is_falsey());
-                        self.push(value);
+                        let top = self.top_mut()?;
+                        *top = Value::Bool(top.is_falsey());
                     }
                     OpCode::Equal => {
-                        let b = self.pop();
-                        let a = self.pop();
+                        let b = self.pop()?;
+                        let a = self.pop()?;
 
-                        self.push(Value::Bool(a.eq(&b)));
+                        self.push(Value::Bool(a.eq(&b, &self.heap)));
                     }
                     OpCode::Greater => binary_op!(self, Value::Bool, >),
                     OpCode::Less => binary_op!(self, Value::Bool, <),
                     OpCode::Print => {
-                        println!("{}", self.pop().to_string(&self.heap));
+                        let value = self.pop()?;
+                        println!("{}", value.to_string(&self.heap));
                     }
                     OpCode::Pop => {
-                        self.pop();
+                        self.pop()?;
+                    }
+                    OpCode::Dup => {
+                        let value = self.peek(0)?;
+                        self.push(value);
                     }
-                    OpCode::GetGlobal => {
-                        let name = self.read_string();
-                        let value = match self.globals.get(&name) {
-                            Some(value) => *value,
+                    OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                        let slot = self.read_global_slot(instruction)?;
+                        let value = match self.globals.get(slot) {
+                            Some(value) => value,
                             None => runtime_error!(
                                 self,
                                 "Undefined variable '{}'",
-                                name.to_string(&self.heap)
+                                self.globals.name(slot).to_string(&self.heap)
                             ),
                         };
                         self.push(value);
                     }
-                    OpCode::DefineGlobal => {
-                        let name = self.read_string();
-                        self.globals.insert(name, self.peek(0));
-                        self.pop();
+                    OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                        let slot = self.read_global_slot(instruction)?;
+                        let value = self.pop()?;
+                        self.globals.set(slot, value);
                     }
-                    OpCode::SetGlobal => {
-                        let name = self.read_string();
-                        if !self.globals.contains_key(&name) {
+                    OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                        let slot = self.read_global_slot(instruction)?;
+                        if !self.globals.is_defined(slot) {
                             runtime_error!(
                                 self,
                                 "Undefined variable '{}'",
-                                name.to_string(&self.heap)
+                                self.globals.name(slot).to_string(&self.heap)
+                            );
+                        }
+                        // The compiler catches most of these, but not in a function compiled
+                        // before the constant is declared
+                        if self.globals.is_const(slot) {
+                            runtime_error!(
+                                self,
+                                "Cannot assign to constant '{}'",
+                                self.globals.name(slot).to_string(&self.heap)
                             );
                         }
-                        self.globals.insert(name, self.peek(0));
-                        // No POP since a `set` is a expression and should return the value
+                        let value = self.pop()?;
+                        self.globals.set(slot, value);
                     }
                     OpCode::GetLocal => {
-                        let slot = self.read_byte() as usize;
+                        let slot = self.read_byte()? as usize;
                         // self.push(self.stack[slot as usize]);
                         let value = self.stack[frame!(self).fp + slot];
                         self.push(value);
                     }
                     OpCode::SetLocal => {
-                        let slot = self.read_byte() as usize;
-                        self.stack[frame!(self).fp + slot] = self.peek(0);
+                        let slot = self.read_byte()? as usize;
+                        let value = self.pop()?;
+                        self.stack[frame!(self).fp + slot] = value;
+                    }
+                    OpCode::GetLocalLong => {
+                        let slot = self.read_short()? as usize;
+                        let value = self.stack[frame!(self).fp + slot];
+                        self.push(value);
+                    }
+                    OpCode::SetLocalLong => {
+                        let slot = self.read_short()? as usize;
+                        let value = self.pop()?;
+                        self.stack[frame!(self).fp + slot] = value;
                     }
                     OpCode::JumpIfFalse => {
-                        let offset = self.read_short();
-                        if self.peek(0).is_falsey() {
+                        let offset = self.read_short()?;
+                        if self.peek(0)?.is_falsey() {
                             frame!(self).ip += offset as usize;
                         }
                     }
                     OpCode::Jump => {
-                        let offset = self.read_short();
+                        let offset = self.read_short()?;
                         frame!(self).ip += offset as usize;
                     }
                     OpCode::Loop => {
-                        let offset = self.read_short();
+                        let offset = self.read_short()?;
                         frame!(self).ip -= offset as usize;
                     }
                     OpCode::Call => {
-                        let arg_count = self.read_byte() as usize;
-                        self.call_value(self.peek(arg_count), arg_count)?;
+                        let arg_count = self.read_byte()? as usize;
+                        let callee = self.peek(arg_count)?;
+                        self.call_value(callee, arg_count)?;
+                    }
+                    OpCode::PushHandler => {
+                        let offset = self.read_short()?;
+                        let frame = frame!(self);
+                        let catch_ip = frame.ip + offset as usize;
+                        self.handlers.push(Handler {
+                            frame_count: self.frames.len(),
+                            stack_top: self.stack_top,
+                            catch_ip,
+                        });
+                    }
+                    OpCode::PopHandler => {
+                        self.handlers.pop();
+                    }
+                    OpCode::Throw => {
+                        let value = self.pop()?;
+                        let handler = match self.handlers.pop() {
+                            Some(handler) => handler,
+                            None => {
+                                runtime_error!(self, "Uncaught {}", value.to_string(&self.heap))
+                            }
+                        };
+                        self.unwind(handler, value);
                     }
                 },
                 Err(err) => {
@@ -404,7 +730,159 @@ impl VM {
     }
 }
 
-fn clockNative(_args: &[Value]) -> Value {
-    let elapsed = START_TIME.read().unwrap().elapsed();
-    Value::Number(elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9)
+// Seconds since the unix epoch, like `clock` in the tree-walker. Counting from the start of
+// `run` would restart for every line in the REPL
+fn clockNative(_args: &[Value], _heap: &mut ObjHeap) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backward!");
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+// assert(condition) or assert(condition, message)
+fn assertNative(args: &[Value], heap: &mut ObjHeap) -> Result<Value, String> {
+    match args {
+        [condition] | [condition, _] if !condition.is_falsey() => Ok(Value::Nil),
+        [_] => Err("Assertion failed".to_owned()),
+        [_, message] => {
+            let message = match message.to_owned_value(heap) {
+                OwnedValue::String(message) => message,
+                _ => message.to_string(heap),
+            };
+            Err(format!("Assertion failed: {}", message))
+        }
+        _ => unreachable!("Arity is checked before calling"),
+    }
+}
+
+// Like `print`, but without the newline
+fn writeNative(args: &[Value], heap: &mut ObjHeap) -> Result<Value, String> {
+    match args {
+        [value] => {
+            match value.to_owned_value(heap) {
+                OwnedValue::String(string) => print!("{}", string),
+                _ => print!("{}", value.to_string(heap)),
+            }
+            Ok(Value::Nil)
+        }
+        _ => unreachable!("Arity is checked before calling"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::ObjFunction;
+
+    // A script function running `code` as its chunk, all on line 1
+    fn function_with_code(code: &[OpCode]) -> ObjFunction {
+        let mut function = ObjFunction::new();
+        for opcode in code {
+            function.chunk.write(*opcode as u8, 1);
+        }
+        function
+    }
+
+    #[test]
+    fn stack_underflow_is_a_runtime_error() {
+        let mut vm = VM::new();
+        // The second Pop takes the script function itself, the third has nothing left
+        let function = function_with_code(&[
+            OpCode::Nil,
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ]);
+
+        match vm.run_function(function) {
+            Err(InterpretError::RuntimeError(error)) => {
+                assert_eq!(error.message, "Corrupt bytecode: stack underflow")
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    // Run with `cargo test --features trace-execution`
+    #[cfg(feature = "trace-execution")]
+    #[test]
+    fn instruction_counts_count_each_loop_iteration() {
+        let mut vm = VM::new();
+        vm.interpret("var i = 0; while (i < 10) i = i + 1;")
+            .unwrap();
+
+        assert_eq!(vm.instruction_counts[OpCode::Loop as usize], 10);
+    }
+
+    #[test]
+    fn stack_is_empty_after_running() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "var a = 1; { var b = a + 2; print b; } fun f(x) { return x * 2; } f(a); \
+             if (a == 1) { a = f(a); } else { print a; } while (a < 10) a = a + 1; \
+             try { throw a; } catch (e) { print e; }",
+        )
+        .unwrap();
+        assert_eq!(vm.stack_len(), 0);
+
+        vm.interpret("print -\"text\";").unwrap_err();
+        assert_eq!(vm.stack_len(), 0);
+    }
+
+    #[test]
+    fn truncated_chunk_is_a_runtime_error() {
+        let mut vm = VM::new();
+        // The constant index is missing
+        let function = function_with_code(&[OpCode::Nil, OpCode::Pop, OpCode::Constant]);
+
+        match vm.run_function(function) {
+            Err(InterpretError::RuntimeError(error)) => {
+                assert_eq!(error.message, "Corrupt bytecode: ip out of range")
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn gc_hint_frees_temporaries_between_lines() {
+        let mut vm = VM::new();
+        vm.interpret("var kept = \"kept\";").unwrap();
+        vm.gc_hint();
+        let len = vm.heap.len();
+
+        for i in 0..100 {
+            let line = format!("{{ var temporary = \"line {}\" + kept; }}", i);
+            vm.interpret(&line).unwrap();
+            vm.gc_hint();
+            assert_eq!(vm.heap.len(), len);
+        }
+        assert_eq!(
+            vm.eval("kept").unwrap(),
+            OwnedValue::String("kept".to_owned())
+        );
+    }
+
+    #[test]
+    fn eval_returns_the_value() {
+        let mut vm = VM::new();
+        assert_eq!(vm.eval("1 + 2").unwrap(), OwnedValue::Number(3.));
+        assert_eq!(
+            vm.eval("\"a\" + \"b\"").unwrap(),
+            OwnedValue::String("ab".to_owned())
+        );
+    }
+
+    #[test]
+    fn more_than_256_globals() {
+        let mut vm = VM::new();
+        // One line at a time, like the REPL
+        for i in 0..300 {
+            vm.interpret(&format!("var g{} = {};", i, i)).unwrap();
+        }
+        vm.interpret("g299 = g299 + g0 + 1;").unwrap();
+
+        assert_eq!(vm.eval("g254").unwrap(), OwnedValue::Number(254.));
+        assert_eq!(vm.eval("g299").unwrap(), OwnedValue::Number(300.));
+    }
 }